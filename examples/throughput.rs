@@ -0,0 +1,108 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Measures the write throughput against a file-backed target.
+//!
+//! Usage: cargo run --release --example throughput [IMAGE | SIZE_MIB]
+//!
+//! Without an image, one of SIZE_MIB (256 by default) is generated in the
+//! temporary directory. The target file is created there too.
+
+extern crate acetylene;
+
+use std::env;
+use std::fs::{self,File};
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::time::Instant;
+
+use acetylene::{BurnConfig,BurnSetting,Progress,burn_image};
+
+fn generate(path: &str, mbytes: usize) {
+    let mut file = File::create(path).unwrap();
+    let mut block = vec![0u8; 1024 * 1024];
+    let mut state: u32 = 0x2545_f491;
+
+    for _ in 0..mbytes {
+        // Cheap xorshift, so that compression doesn't skew the figures
+        for byte in block.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+
+        file.write_all(&block).unwrap();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let dir = env::temp_dir();
+    let target = dir.join("acetylene-throughput.out").to_string_lossy().into_owned();
+
+    let generated = match args.get(1) {
+        Some(arg) => arg.parse().ok(),
+        None => Some(256),
+    };
+    let image = match generated {
+        Some(mbytes) => {
+            let image = dir.join("acetylene-throughput.img").to_string_lossy().into_owned();
+
+            generate(&image, mbytes);
+
+            image
+        }
+        None => args[1].clone(),
+    };
+
+    let (tx, rx) = channel();
+    let config = BurnConfig {
+        device: target.clone(),
+        image: image.clone(),
+        entry: None,
+        bmap: None,
+        sync_interval: None,
+        progress_interval: None,
+        checksum: None,
+        hash_algorithms: vec![],
+        signature: None,
+        settings: vec![BurnSetting::IgnoreBmap, BurnSetting::AllowFileTarget],
+    };
+
+    let start = Instant::now();
+    let result = burn_image(config, tx);
+    let elapsed = start.elapsed();
+
+    let written = rx.iter().fold(0, |written, event| match event {
+        Progress::Progress { count, .. } => count,
+        _ => written,
+    });
+
+    let _ = fs::remove_file(&target);
+
+    if generated.is_some() {
+        let _ = fs::remove_file(&image);
+    }
+
+    if let Err(e) = result {
+        println!("Error: {}", e);
+        return;
+    }
+
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+    println!("{} bytes in {:.2} s: {:.1} MiB/s", written, seconds, written as f64 / seconds / (1024.0 * 1024.0));
+}
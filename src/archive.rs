@@ -0,0 +1,183 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Images stored inside ZIP archives.
+//!
+//! Only the central directory is read through the `zip` crate; the entry
+//! data is then streamed straight from the archive file, so that it can be
+//! decompressed without borrowing the archive.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use zip::{CompressionMethod,ZipArchive};
+
+use error::{Error,Result};
+
+/// Extensions identifying disk images among the entries of an archive.
+const IMAGE_EXTENSIONS: &[&str] = &[".img", ".raw", ".iso", ".bin"];
+
+/// Offset of the general purpose flags in a local file header
+const LOCAL_HEADER_FLAGS: u64 = 6;
+const FLAG_ENCRYPTED: u8 = 1;
+
+/// Location of an entry in a ZIP archive
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Name of the entry in the archive
+    pub name: String,
+    /// Offset of the entry data in the archive file
+    pub data_start: u64,
+    /// Size of the stored data
+    pub compressed_size: u64,
+    /// Size of the extracted data
+    pub size: u64,
+    /// Whether the data is deflated, it is stored as is otherwise
+    pub deflated: bool,
+    /// CRC-32 of the extracted data
+    pub crc32: u32,
+}
+
+/// Locates the image in the archive.
+///
+/// With no `name`, the image is the only file of the archive, or else its
+/// only file with a disk image extension.
+pub fn find_entry(file: &File, name: Option<&str>) -> Result<Entry> {
+    let mut archive = ZipArchive::new(file).map_err(|e| Error::InvalidArchive(e.to_string()))?;
+
+    let mut files = Vec::new();
+
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(|e| Error::InvalidArchive(e.to_string()))?;
+
+        if entry.is_file() {
+            files.push((index, entry.name().to_owned()));
+        }
+    }
+
+    let index = match name {
+        Some(name) => match files.iter().find(|(_, file)| file == name) {
+            Some(&(index, _)) => index,
+            None => return Err(Error::EntryNotFound(name.to_owned())),
+        },
+        None if files.len() == 1 => files[0].0,
+        None => {
+            let images: Vec<_> = files.iter()
+                .filter(|(_, file)| {
+                    let file = file.to_lowercase();
+                    IMAGE_EXTENSIONS.iter().any(|extension| file.ends_with(extension))
+                })
+                .collect();
+
+            match images.len() {
+                1 => images[0].0,
+                0 => return Err(Error::AmbiguousArchive(files.into_iter().map(|(_, file)| file).collect())),
+                _ => return Err(Error::AmbiguousArchive(images.into_iter().map(|(_, file)| file.clone()).collect())),
+            }
+        }
+    };
+
+    let entry = archive.by_index_raw(index).map_err(|e| Error::InvalidArchive(e.to_string()))?;
+
+    let deflated = match entry.compression() {
+        CompressionMethod::Stored => false,
+        CompressionMethod::Deflated => true,
+        method => return Err(Error::InvalidArchive(format!(
+            "{} uses the unsupported {} compression", entry.name(), method
+        ))),
+    };
+
+    // The central directory isn't exposed, the local header carries the same flags
+    let mut flags = [0u8; 2];
+
+    file.read_exact_at(&mut flags, entry.header_start() + LOCAL_HEADER_FLAGS)
+        .map_err(|e| Error::InvalidArchive(e.to_string()))?;
+
+    if flags[0] & FLAG_ENCRYPTED != 0 {
+        return Err(Error::InvalidArchive(format!("{} is encrypted", entry.name())));
+    }
+
+    Ok(Entry {
+        name: entry.name().to_owned(),
+        data_start: entry.data_start(),
+        compressed_size: entry.compressed_size(),
+        size: entry.size(),
+        deflated,
+        crc32: entry.crc32(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{remove_file,write};
+    use std::io::{Cursor,Write};
+    use std::process;
+
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    /// Archive holding `files`, stored as is
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        for &(name, content) in files {
+            archive.start_file(name, options).unwrap();
+            archive.write_all(content).unwrap();
+        }
+
+        archive.finish().unwrap().into_inner()
+    }
+
+    fn find(name: &str, archive: &[u8], entry: Option<&str>) -> Result<Entry> {
+        let path = env::temp_dir().join(format!("acetylene-archive-{}-{}", name, process::id()));
+
+        write(&path, archive).unwrap();
+
+        let result = find_entry(&File::open(&path).unwrap(), entry);
+
+        let _ = remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn images_are_found_by_extension() {
+        let archive = archive(&[("README", b"read me"), ("disk.img", b"image"), ("notes.txt", b"")]);
+        let entry = find("extension", &archive, None).unwrap();
+
+        assert_eq!(entry.name, "disk.img");
+        assert_eq!(entry.size, 5);
+        assert_eq!(entry.crc32, crc32fast::hash(b"image"));
+        assert!(!entry.deflated);
+
+        assert!(matches!(find("named", &archive, Some("other.img")), Err(Error::EntryNotFound(_))));
+    }
+
+    #[test]
+    fn encrypted_entries_are_refused() {
+        let mut archive = archive(&[("disk.img", b"image")]);
+
+        // General purpose flags of the local header of the only entry
+        archive[LOCAL_HEADER_FLAGS as usize] |= FLAG_ENCRYPTED;
+
+        match find("encrypted", &archive, None) {
+            Err(Error::InvalidArchive(reason)) => assert_eq!(reason, "disk.img is encrypted"),
+            _ => panic!("encrypted entry accepted"),
+        }
+    }
+}
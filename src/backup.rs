@@ -0,0 +1,320 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Capture of a device back into an image file.
+
+use std::fs::{self,File};
+use std::io::{self,Read,Seek,SeekFrom,Write};
+use std::time::Duration;
+
+use bzip2::write::BzEncoder;
+use bzip2::Compression as BzLevel;
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use xz2::write::XzEncoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use blockdev;
+use cancel::CancelToken;
+use checksum::{Digests,HashAlgorithm,Hashers};
+use error::{Error,Result};
+use meter::Meter;
+use partitions::{self,PartitionTable};
+use source::{self,Compression};
+use {Progress,ProgressSink,cancelled,report};
+
+const BUFFER_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+const XZ_PRESET: u32 = 6;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BackupSetting {
+    /// Stop at the end of the last partition rather than at the end of the
+    /// device. The backup of a GPT is moved right after the last partition.
+    Truncate,
+}
+
+#[derive(Clone)]
+pub struct BackupConfig {
+    /// Device to read
+    pub device: String,
+    /// Image file to create
+    pub image: String,
+    /// How to compress the image, ZIP archives aren't supported
+    pub compression: Compression,
+    /// Report progress at most once every that long, after every buffer
+    /// when `None`
+    pub progress_interval: Option<Duration>,
+    /// Settings
+    pub settings: Vec<BackupSetting>,
+}
+
+/// Compressor in front of the image file
+enum Encoder {
+    Raw(File),
+    Gzip(GzEncoder<File>),
+    Xz(XzEncoder<File>),
+    Bzip2(BzEncoder<File>),
+    Zstd(ZstdEncoder<'static, File>),
+}
+
+impl Encoder {
+    fn new(file: File, compression: Compression) -> io::Result<Encoder> {
+        Ok(match compression {
+            Compression::None => Encoder::Raw(file),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(file, GzLevel::default())),
+            Compression::Xz => Encoder::Xz(XzEncoder::new(file, XZ_PRESET)),
+            Compression::Bzip2 => Encoder::Bzip2(BzEncoder::new(file, BzLevel::default())),
+            Compression::Zstd => Encoder::Zstd(ZstdEncoder::new(file, ZSTD_LEVEL)?),
+            Compression::Zip => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't write ZIP archives"));
+            }
+        })
+    }
+
+    /// Writes the compressed stream trailer, and hands the file back.
+    fn finish(self) -> io::Result<File> {
+        match self {
+            Encoder::Raw(file) => Ok(file),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match *self {
+            Encoder::Raw(ref mut file) => file,
+            Encoder::Gzip(ref mut encoder) => encoder,
+            Encoder::Xz(ref mut encoder) => encoder,
+            Encoder::Bzip2(ref mut encoder) => encoder,
+            Encoder::Zstd(ref mut encoder) => encoder,
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+/// Data to write over the captured one, and its offset
+type Patch = (u64, Vec<u8>);
+
+/// Overwrites what `patches` cover of `buf`, read at `offset`.
+fn apply(patches: &[Patch], offset: u64, buf: &mut [u8]) {
+    let end = offset + buf.len() as u64;
+
+    for &(start, ref data) in patches {
+        let from = start.max(offset);
+        let to = (start + data.len() as u64).min(end);
+
+        if from < to {
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+}
+
+/// Device whose writes are kept aside, as patches to the captured data.
+struct Patched<'a> {
+    device: &'a mut File,
+    position: u64,
+    patches: Vec<Patch>,
+}
+
+impl<'a> Read for Patched<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.device.read(buf)?;
+
+        apply(&self.patches, self.position, &mut buf[..n]);
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<'a> Write for Patched<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.patches.push((self.position, buf.to_vec()));
+        self.seek(SeekFrom::Current(buf.len() as i64))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for Patched<'a> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.device.seek(position)?;
+
+        Ok(self.position)
+    }
+}
+
+/// Size of the capture of `device`, `capacity` bytes long, and the patches
+/// to apply to it.
+///
+/// When truncating, the capture ends with the last partition. The backup of
+/// a GPT is then moved right after it, so that the image holds a whole GPT.
+fn extent(device: &mut File, capacity: u64, truncate: bool) -> Result<(u64, Vec<Patch>)> {
+    if !truncate {
+        return Ok((capacity, vec![]));
+    }
+
+    let table = match PartitionTable::read(device, capacity)? {
+        Some(table) => table,
+        None => return Ok((capacity, vec![])),
+    };
+
+    let end = table.end().map_or(capacity, |end| end.min(capacity));
+
+    let (sector_size, header) = match table {
+        PartitionTable::Gpt { sector_size, ref header, .. } => (sector_size, header),
+        PartitionTable::Mbr { .. } => {
+            partitions::check_mbr_sectors(blockdev::logical_sector_size(device)?)?;
+
+            return Ok((end, vec![]));
+        }
+    };
+
+    // Backup entries, then backup header
+    let backup = (header.entries_size().div_ceil(sector_size) + 1) * sector_size;
+    let total = (end.div_ceil(sector_size) * sector_size + backup).min(capacity);
+
+    let mut patched = Patched { device, position: 0, patches: vec![] };
+
+    partitions::relocate_gpt_backup(&mut patched, total)?;
+
+    Ok((total, patched.patches))
+}
+
+/// Copies `total` bytes of `device` into `output`, with `patches` applied,
+/// hashing them on the way.
+fn copy(device: &mut File, output: &mut Encoder, total: u64, patches: &[Patch], interval: Option<Duration>,
+        tx: &dyn ProgressSink, cancel: &CancelToken)
+    -> Result<(u64, Digests)>
+{
+    let mut hasher = Hashers::new(&[HashAlgorithm::Sha256]);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut count = 0;
+
+    tx.send(Progress::Start { total }).map_err(|_| Error::Cancelled)?;
+
+    let mut meter = Meter::new(interval);
+
+    while count < total {
+        cancel.check()?;
+
+        let size = (total - count).min(BUFFER_SIZE as u64) as usize;
+        let n = source::read_full(device, &mut buffer[..size])
+            .map_err(|e| report(tx, Error::Read { offset: count, cause: e }, count))?;
+
+        if n < size {
+            let cause = io::Error::new(io::ErrorKind::UnexpectedEof, "device shorter than expected");
+            return Err(report(tx, Error::Read { offset: count + n as u64, cause }, count));
+        }
+
+        apply(patches, count, &mut buffer[..n]);
+        hasher.input(&buffer[..n]);
+        output.write_all(&buffer[..n])
+            .map_err(|e| report(tx, Error::Write { offset: count, cause: e }, count))?;
+
+        count += n as u64;
+
+        if let Some(throughput) = meter.update(count, total) {
+            tx.send(Progress::Progress { count, total, throughput }).map_err(|_| Error::Cancelled)?;
+        }
+    }
+
+    Ok((count, hasher.result()))
+}
+
+/// Reads the device back into an image file, see `backup_device_cancellable`.
+pub fn backup_device<S: ProgressSink>(config: BackupConfig, tx: S) -> Result<()> {
+    backup_device_cancellable(config, tx, CancelToken::new())
+}
+
+/// Reads the device back into an image file, compressing it on the fly.
+///
+/// Progress is counted in bytes read from the device. `Progress::End`
+/// carries the SHA-256 of the captured data, before compression. The image
+/// file is removed if the capture fails or is cancelled.
+pub fn backup_device_cancellable<S: ProgressSink>(config: BackupConfig, tx: S, cancel: CancelToken) -> Result<()> {
+    let mut device = File::open(&config.device)
+        .map_err(|e| report(&tx, Error::device_open(&config.device, e), 0))?;
+    let capacity = blockdev::file_size(&device)
+        .map_err(|e| report(&tx, Error::Io(e), 0))?;
+
+    let (total, patches) = extent(&mut device, capacity, config.settings.contains(&BackupSetting::Truncate))
+        .map_err(|e| report(&tx, e, 0))?;
+
+    device.seek(SeekFrom::Start(0)).map_err(|e| report(&tx, Error::Io(e), 0))?;
+
+    let file = File::create(&config.image)
+        .map_err(|e| report(&tx, Error::image_open(&config.image, e), 0))?;
+    let mut output = Encoder::new(file, config.compression)
+        .map_err(|e| report(&tx, Error::Io(e), 0))?;
+
+    let result = copy(&mut device, &mut output, total, &patches, config.progress_interval, &tx, &cancel).and_then(|(count, digest)| {
+        output.finish()
+            .and_then(|file| file.sync_all())
+            .map_err(|e| report(&tx, Error::Write { offset: count, cause: e }, count))?;
+
+        Ok(digest)
+    });
+
+    let digest = match result {
+        Ok(digest) => digest,
+        Err(e) => {
+            let _ = fs::remove_file(&config.image);
+
+            return Err(match e {
+                Error::Cancelled => cancelled(&tx, 0),
+                e => e,
+            });
+        }
+    };
+
+    // The capture is complete, whether someone is still listening or not
+    let _ = tx.send(Progress::End { digests: Some(digest) });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+
+    #[test]
+    fn patches_apply_where_they_overlap() {
+        let patches = vec![(2, b"abc".to_vec()), (7, b"xyz".to_vec()), (20, b"far".to_vec())];
+        let mut buf = *b"........";
+
+        apply(&patches, 1, &mut buf);
+
+        assert_eq!(&buf, b".abc..xy");
+    }
+}
@@ -0,0 +1,187 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Low level operations on block devices.
+
+use std::io;
+use std::fs::{File,Metadata};
+use std::path::Path;
+
+use error::{Error,Result};
+
+#[cfg(target_os = "linux")]
+use libc;
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use std::mem;
+
+    use libc::c_ulong;
+
+    const IOC_READ: c_ulong = 2;
+
+    /// `_IOR(0x12, 114, size_t)`, from linux/fs.h
+    pub const BLKGETSIZE64: c_ulong =
+        (IOC_READ << 30) | ((mem::size_of::<usize>() as c_ulong) << 16) | (0x12 << 8) | 114;
+    /// `_IO(0x12, 95)`
+    pub const BLKRRPART: c_ulong = (0x12 << 8) | 95;
+    /// `_IO(0x12, 97)`
+    pub const BLKFLSBUF: c_ulong = (0x12 << 8) | 97;
+    /// `_IO(0x12, 104)`
+    pub const BLKSSZGET: c_ulong = (0x12 << 8) | 104;
+}
+
+/// Issues an ioctl taking no argument.
+#[cfg(target_os = "linux")]
+fn ioctl_none(file: &File, request: libc::c_ulong) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // The request type differs between libc implementations, hence the cast.
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Makes the kernel read the partition table of the device again.
+///
+/// Fails with `EBUSY` while one of its partitions is in use.
+#[cfg(target_os = "linux")]
+pub fn reread_partitions(file: &File) -> io::Result<()> {
+    ioctl_none(file, ioctl::BLKRRPART)
+}
+
+/// Writes back and drops the buffer cache of the device.
+#[cfg(target_os = "linux")]
+pub fn flush_buffers(file: &File) -> io::Result<()> {
+    ioctl_none(file, ioctl::BLKFLSBUF)
+}
+
+/// Size in bytes of an opened block device or regular file.
+#[cfg(target_os = "linux")]
+pub fn file_size(file: &File) -> io::Result<u64> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    let meta = file.metadata()?;
+
+    if !meta.file_type().is_block_device() {
+        return Ok(meta.len());
+    }
+
+    let mut size: u64 = 0;
+
+    // The request type differs between libc implementations, hence the cast.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), ioctl::BLKGETSIZE64 as _, &mut size) };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size)
+}
+
+/// Logical sector size of an opened block device, 512 bytes for regular files.
+#[cfg(target_os = "linux")]
+pub fn logical_sector_size(file: &File) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    if !is_block_device(file) {
+        return Ok(512);
+    }
+
+    let mut size: libc::c_int = 0;
+
+    // The request type differs between libc implementations, hence the cast.
+    if unsafe { libc::ioctl(file.as_raw_fd(), ioctl::BLKSSZGET as _, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size as u64)
+}
+
+/// Logical sector size of an opened block device, 512 bytes for regular files.
+#[cfg(not(target_os = "linux"))]
+pub fn logical_sector_size(_file: &File) -> io::Result<u64> {
+    Ok(512)
+}
+
+/// Size in bytes of an opened block device or regular file.
+#[cfg(not(target_os = "linux"))]
+pub fn file_size(file: &File) -> io::Result<u64> {
+    use std::io::{Seek,SeekFrom};
+
+    let meta = file.metadata()?;
+
+    if meta.is_file() {
+        return Ok(meta.len());
+    }
+
+    // Seeking doesn't alter the file, only the cursor of this handle
+    let mut file = file;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+
+    Ok(size)
+}
+
+/// Whether `meta` is the one of a block device.
+///
+/// Character devices, such as `/dev/null`, and directories aren't.
+#[cfg(unix)]
+pub fn is_block(meta: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    meta.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+pub fn is_block(_meta: &Metadata) -> bool {
+    false
+}
+
+/// Whether the opened file is a block device.
+pub fn is_block_device(file: &File) -> bool {
+    file.metadata().map(|meta| is_block(&meta)).unwrap_or(false)
+}
+
+/// Get the exact capacity in bytes of a block device or an image file.
+pub fn get_device_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .map_err(|e| Error::device_open(&path.to_string_lossy(), e))?;
+
+    Ok(file_size(&file)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+
+    use super::is_block_device;
+
+    #[cfg(unix)]
+    #[test]
+    fn character_devices_are_not_block_devices() {
+        assert!(!is_block_device(&File::open("/dev/null").unwrap()));
+    }
+
+    #[test]
+    fn regular_files_are_not_block_devices() {
+        assert!(!is_block_device(&File::open(env::current_exe().unwrap()).unwrap()));
+    }
+}
@@ -0,0 +1,290 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Block maps, as produced by bmaptool.
+//!
+//! A block map lists the blocks of an image that hold data, along with a
+//! checksum of every range of blocks. Unmapped blocks need not be written.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path,PathBuf};
+
+use regex::Regex;
+
+use checksum::unhex;
+use error::{Error,Result};
+
+/// Hash function of the range checksums
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumType {
+    /// Version 1 maps, and some version 2 ones
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumType {
+    /// Length of the digests, in bytes
+    fn digest_len(self) -> usize {
+        match self {
+            ChecksumType::Sha1 => 20,
+            ChecksumType::Sha256 => 32,
+        }
+    }
+}
+
+/// Range of mapped blocks, bounds included
+#[derive(Clone, Debug)]
+pub struct Range {
+    pub first: u64,
+    pub last: u64,
+    /// Checksum of the range data, of the `checksum_type` of the map
+    pub checksum: Option<Vec<u8>>,
+}
+
+/// Block map of an image
+#[derive(Clone, Debug)]
+pub struct Bmap {
+    /// Size of the image, in bytes
+    pub image_size: u64,
+    /// Size of the blocks, in bytes
+    pub block_size: u64,
+    pub checksum_type: ChecksumType,
+    /// Mapped ranges, in ascending order
+    pub ranges: Vec<Range>,
+}
+
+impl Bmap {
+    /// Parses the XML content of a bmap file (versions 1.x and 2.x).
+    pub fn parse(content: &str) -> Result<Bmap> {
+        lazy_static! {
+            static ref COMMENT: Regex = Regex::new(r"(?s)<!--.*?-->").unwrap();
+            static ref RANGE: Regex = Regex::new(
+                r#"<Range(?:\s+(?:chksum|sha1)="([0-9a-fA-F]*)")?\s*>\s*(\d+)(?:\s*-\s*(\d+))?\s*</Range>"#
+            ).unwrap();
+        }
+
+        let invalid = |reason: String| Error::InvalidBmap(reason);
+
+        let content = COMMENT.replace_all(content, "");
+
+        let field = |name: &str| -> Result<String> {
+            let re = Regex::new(&format!(r"<{0}>\s*([^<]*?)\s*</{0}>", name)).unwrap();
+
+            re.captures(&content)
+                .map(|caps| caps[1].to_owned())
+                .ok_or_else(|| invalid(format!("missing {}", name)))
+        };
+        let number = |name: &str| -> Result<u64> {
+            field(name)?.parse().map_err(|_| invalid(format!("invalid {}", name)))
+        };
+
+        let image_size = number("ImageSize")?;
+        let block_size = number("BlockSize")?;
+
+        if block_size == 0 {
+            return Err(invalid("invalid BlockSize".to_owned()));
+        }
+
+        // Version 1 maps have SHA-1 checksums, and no type
+        let checksum_type = match field("ChecksumType") {
+            Ok(ref kind) if kind == "sha256" => ChecksumType::Sha256,
+            Ok(ref kind) if kind == "sha1" => ChecksumType::Sha1,
+            Ok(kind) => return Err(invalid(format!("unsupported checksum type {}", kind))),
+            Err(_) => ChecksumType::Sha1,
+        };
+
+        let mut ranges = Vec::new();
+
+        for caps in RANGE.captures_iter(&content) {
+            let first: u64 = caps[2].parse().map_err(|_| invalid("invalid range".to_owned()))?;
+            let last: u64 = match caps.get(3) {
+                Some(last) => last.as_str().parse().map_err(|_| invalid("invalid range".to_owned()))?,
+                None => first,
+            };
+            let checksum = match caps.get(1) {
+                Some(hex) => Some(unhex(hex.as_str()).filter(|digest| digest.len() == checksum_type.digest_len())
+                    .ok_or_else(|| invalid(format!("invalid checksum of range {}-{}", first, last)))?),
+                None => None,
+            };
+
+            if last < first || ranges.last().is_some_and(|range: &Range| range.last >= first) {
+                return Err(invalid(format!("invalid range {}-{}", first, last)));
+            }
+
+            // The last block may end past the image, not start there
+            let start = first.checked_mul(block_size).filter(|&start| start < image_size);
+            let end = last.checked_add(1).and_then(|end| end.checked_mul(block_size));
+
+            if start.is_none() || end.is_none() {
+                return Err(invalid(format!("range {}-{} out of the image", first, last)));
+            }
+
+            ranges.push(Range { first, last, checksum });
+        }
+
+        Ok(Bmap { image_size, block_size, checksum_type, ranges })
+    }
+
+    /// Fails unless the map is made for an image of `size` bytes.
+    pub fn check_image_size(&self, size: u64) -> Result<()> {
+        if size != self.image_size {
+            return Err(Error::InvalidBmap(format!(
+                "made for an image of {} bytes, not {}", self.image_size, size
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Bmap> {
+        let mut content = String::new();
+
+        File::open(path)?.read_to_string(&mut content)?;
+
+        Bmap::parse(&content)
+    }
+
+    /// Looks for the block map of `image` next to it.
+    ///
+    /// For "foo.img.xz", "foo.img.xz.bmap", "foo.img.bmap" and "foo.bmap"
+    /// are tried in that order.
+    pub fn find_for<P: AsRef<Path>>(image: P) -> Option<PathBuf> {
+        let mut stem = image.as_ref().to_path_buf();
+
+        loop {
+            let mut candidate = stem.clone().into_os_string();
+            candidate.push(".bmap");
+            let candidate = PathBuf::from(candidate);
+
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+
+            stem.extension()?;
+            stem.set_extension("");
+        }
+    }
+
+    /// Byte span `[start, end)` of `range`, clamped to the image size.
+    pub fn span(&self, range: &Range) -> (u64, u64) {
+        let start = range.first.saturating_mul(self.block_size);
+        let end = range.last.saturating_add(1).saturating_mul(self.block_size);
+
+        (start.min(self.image_size), end.min(self.image_size))
+    }
+
+    /// Number of bytes to write.
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges.iter().map(|range| {
+            let (start, end) = self.span(range);
+            end - start
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    /// Version 2 map of a 10 block image with `ranges`
+    fn v2(ranges: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" ?>\n<bmap version=\"2.0\">\n    <ImageSize> 40960 </ImageSize>\n\
+             <BlockSize> 4096 </BlockSize>\n    <BlocksCount> 10 </BlocksCount>\n\
+             <!-- <ChecksumType> sha1 </ChecksumType> -->\n\
+             <ChecksumType> sha256 </ChecksumType>\n    <BlockMap>\n{}    </BlockMap>\n</bmap>\n",
+            ranges
+        )
+    }
+
+    fn reason(content: &str) -> String {
+        match Bmap::parse(content) {
+            Err(Error::InvalidBmap(reason)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(bmap) => panic!("accepted {:?}", bmap),
+        }
+    }
+
+    #[test]
+    fn version_2_maps_are_parsed() {
+        let bmap = Bmap::parse(&v2(&format!(
+            "        <Range chksum=\"{0}\"> 0-1 </Range>\n        <Range chksum=\"{0}\"> 5 </Range>\n\
+             <Range>9</Range>\n",
+            SHA256
+        ))).unwrap();
+
+        assert_eq!(bmap.image_size, 40960);
+        assert_eq!(bmap.block_size, 4096);
+        assert_eq!(bmap.checksum_type, ChecksumType::Sha256);
+        assert_eq!(bmap.ranges.len(), 3);
+        assert_eq!((bmap.ranges[1].first, bmap.ranges[1].last), (5, 5));
+        assert_eq!(bmap.ranges[0].checksum, unhex(SHA256));
+        assert_eq!(bmap.ranges[2].checksum, None);
+        assert_eq!(bmap.span(&bmap.ranges[0]), (0, 8192));
+        assert_eq!(bmap.mapped_size(), 4 * 4096);
+    }
+
+    #[test]
+    fn version_1_maps_have_sha1_checksums() {
+        let bmap = Bmap::parse(&format!(
+            "<bmap version=\"1.3\">\n<ImageSize>10000</ImageSize>\n<BlockSize>4096</BlockSize>\n\
+             <BlockMap>\n<Range sha1=\"{}\">1-2</Range>\n</BlockMap>\n</bmap>\n",
+            SHA1
+        )).unwrap();
+
+        assert_eq!(bmap.checksum_type, ChecksumType::Sha1);
+        assert_eq!(bmap.ranges[0].checksum, unhex(SHA1));
+
+        // The last block is cut at the end of the image
+        assert_eq!(bmap.span(&bmap.ranges[0]), (4096, 10000));
+    }
+
+    #[test]
+    fn checksums_must_match_their_type() {
+        let content = v2(&format!("<Range chksum=\"{}\">0</Range>\n", SHA1));
+
+        assert_eq!(reason(&content), "invalid checksum of range 0-0");
+        assert_eq!(reason(&content.replace("sha256", "md5")), "unsupported checksum type md5");
+    }
+
+    #[test]
+    fn ranges_must_be_sorted_and_disjoint() {
+        assert_eq!(reason(&v2("<Range>4-5</Range>\n<Range>1-2</Range>\n")), "invalid range 1-2");
+        assert_eq!(reason(&v2("<Range>1-4</Range>\n<Range>4-5</Range>\n")), "invalid range 4-5");
+        assert_eq!(reason(&v2("<Range>3-2</Range>\n")), "invalid range 3-2");
+    }
+
+    #[test]
+    fn ranges_must_be_in_the_image() {
+        assert_eq!(reason(&v2("<Range>10</Range>\n")), "range 10-10 out of the image");
+        assert_eq!(reason(&v2("<Range>9-18446744073709551615</Range>\n")),
+                   "range 9-18446744073709551615 out of the image");
+        assert_eq!(reason(&v2("<Range>4503599627370496</Range>\n")),
+                   "range 4503599627370496-4503599627370496 out of the image");
+    }
+
+    #[test]
+    fn image_sizes_are_compared() {
+        let bmap = Bmap::parse(&v2("")).unwrap();
+
+        assert!(bmap.check_image_size(40960).is_ok());
+        assert_eq!(bmap.check_image_size(40961).unwrap_err().to_string(),
+                   "invalid block map: made for an image of 40960 bytes, not 40961");
+    }
+}
@@ -0,0 +1,538 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Checksums published along images, e.g. in SHA256SUMS files.
+//!
+//! They cover the image file as distributed, compressed or not, so the
+//! file is hashed as it is read, underneath the decompression.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::fmt;
+use std::fs::File;
+use std::io::{self,Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use blake2::Blake2b512;
+use blake3;
+use crc32fast::Hasher as Crc32;
+use md5::Md5;
+use sha2::{Sha256,Sha512,Digest};
+
+use error::{Error,Result};
+
+const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// Hash function of digests and checksums
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    /// BLAKE2b-512, as computed by b2sum
+    Blake2b,
+    Blake3,
+    /// CRC-32 of zlib and gzip, as a big endian number
+    Crc32,
+    /// Only for the legacy checksums of some vendors
+    Md5,
+}
+
+impl HashAlgorithm {
+    /// Size of the digests, in bytes.
+    pub fn digest_size(&self) -> usize {
+        match *self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+            HashAlgorithm::Blake2b => 64,
+            HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Crc32 => 4,
+            HashAlgorithm::Md5 => 16,
+        }
+    }
+
+    /// Parses names such as "sha256" or "SHA256", as in BSD checksum files.
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            "blake2b" | "blake2b512" => Some(HashAlgorithm::Blake2b),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "crc32" => Some(HashAlgorithm::Crc32),
+            "md5" => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+
+    /// Guesses the algorithm from the length of a digest, SHA-2 being
+    /// assumed for the lengths shared with BLAKE2b and BLAKE3.
+    fn from_size(size: usize) -> Option<HashAlgorithm> {
+        [HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Md5, HashAlgorithm::Crc32].iter().cloned()
+            .find(|algorithm| algorithm.digest_size() == size)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashAlgorithm::Sha256 => write!(f, "SHA256"),
+            HashAlgorithm::Sha512 => write!(f, "SHA512"),
+            HashAlgorithm::Blake2b => write!(f, "BLAKE2b"),
+            HashAlgorithm::Blake3 => write!(f, "BLAKE3"),
+            HashAlgorithm::Crc32 => write!(f, "CRC32"),
+            HashAlgorithm::Md5 => write!(f, "MD5"),
+        }
+    }
+}
+
+/// Hash function state
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake2b(Box<Blake2b512>),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(Crc32),
+    Md5(Md5),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::default()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::default()),
+            HashAlgorithm::Blake2b => Hasher::Blake2b(Box::default()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Crc32 => Hasher::Crc32(Crc32::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Sha256(ref mut hasher) => hasher.update(data),
+            Hasher::Sha512(ref mut hasher) => hasher.update(data),
+            Hasher::Blake2b(ref mut hasher) => hasher.update(data),
+            Hasher::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Crc32(ref mut hasher) => hasher.update(data),
+            Hasher::Md5(ref mut hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn result(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake2b(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Zeros standing for the blocks skipped by block maps and sparse images
+pub static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// Several hash functions fed the same data, so that it is read once.
+pub struct Hashers {
+    hashers: Vec<(HashAlgorithm, Hasher)>,
+}
+
+impl Hashers {
+    pub fn new(algorithms: &[HashAlgorithm]) -> Hashers {
+        let mut algorithms = algorithms.to_vec();
+        algorithms.sort();
+        algorithms.dedup();
+
+        Hashers {
+            hashers: algorithms.into_iter().map(|algorithm| (algorithm, Hasher::new(algorithm))).collect(),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        for &mut (_, ref mut hasher) in &mut self.hashers {
+            hasher.input(data);
+        }
+    }
+
+    /// Hashes `length` zeros, as the blocks left out of a block map or a
+    /// sparse image read.
+    pub fn input_zeros(&mut self, mut length: u64) {
+        if self.hashers.is_empty() {
+            return;
+        }
+
+        while length > 0 {
+            let n = length.min(ZEROS.len() as u64) as usize;
+
+            self.input(&ZEROS[..n]);
+            length -= n as u64;
+        }
+    }
+
+    pub fn result(self) -> Digests {
+        Digests {
+            digests: self.hashers.into_iter().map(|(algorithm, hasher)| (algorithm, hasher.result())).collect(),
+        }
+    }
+}
+
+/// Digests of the same data, by algorithm
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Digests {
+    digests: BTreeMap<HashAlgorithm, Vec<u8>>,
+}
+
+impl Digests {
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&[u8]> {
+        self.digests.get(&algorithm).map(|digest| &digest[..])
+    }
+
+    /// Lower case hexadecimal form of the digest by `algorithm`, as printed
+    /// by sha256sum and the like.
+    pub fn hex(&self, algorithm: HashAlgorithm) -> Option<String> {
+        self.get(algorithm).map(hex)
+    }
+
+    /// Digest by `algorithm`, as a checksum to compare images to.
+    pub fn checksum(&self, algorithm: HashAlgorithm) -> Option<Checksum> {
+        self.get(algorithm).map(|digest| Checksum { algorithm, digest: digest.to_owned() })
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, HashAlgorithm, Vec<u8>> {
+        self.digests.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Digests {
+    type Item = (&'a HashAlgorithm, &'a Vec<u8>);
+    type IntoIter = btree_map::Iter<'a, HashAlgorithm, Vec<u8>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.digests.iter()
+    }
+}
+
+/// One "algorithm:hex" checksum per line, ordered by algorithm.
+impl fmt::Display for Digests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (&algorithm, digest)) in self.digests.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", Checksum { algorithm, digest: digest.clone() })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lower case hexadecimal form of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes hexadecimal text, in either case.
+pub fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+/// Expected digest of an image file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    /// Parses a hexadecimal digest, possibly prefixed by its algorithm, as in
+    /// "sha256:9f86d0…". The algorithm is otherwise told by the length.
+    pub fn parse(text: &str) -> Result<Checksum> {
+        let text = text.trim();
+        let (algorithm, digest) = match text.find(':') {
+            Some(colon) => (HashAlgorithm::from_name(&text[..colon]), &text[colon + 1..]),
+            None => (None, text),
+        };
+
+        let invalid = || Error::InvalidChecksum(format!("{} isn't a known digest", text));
+        let digest = unhex(digest).ok_or_else(invalid)?;
+        let algorithm = algorithm.or_else(|| HashAlgorithm::from_size(digest.len())).ok_or_else(invalid)?;
+
+        if digest.len() != algorithm.digest_size() {
+            return Err(invalid());
+        }
+
+        Ok(Checksum { algorithm, digest })
+    }
+
+    /// Finds the checksum of the file `name` in the content of a checksum
+    /// file, in either GNU ("<digest>  <name>") or BSD ("SHA256 (<name>) =
+    /// <digest>") format.
+    ///
+    /// Entries may name the file with a directory, only the file names are
+    /// compared when no entry matches exactly.
+    pub fn find(sums: &str, name: &str) -> Result<Checksum> {
+        let entries: Vec<(String, &str, Option<HashAlgorithm>)> = sums.lines().filter_map(parse_line).collect();
+
+        let file_name = |path: &str| Path::new(path).file_name().map(|name| name.to_owned());
+
+        let (_, digest, algorithm) = entries.iter().find(|entry| entry.0 == name)
+            .or_else(|| entries.iter().find(|entry| file_name(&entry.0) == file_name(name)))
+            .ok_or_else(|| Error::InvalidChecksum(format!("no checksum listed for {}", name)))?;
+
+        match *algorithm {
+            Some(algorithm) => Checksum::parse(&format!("{}:{}", algorithm, digest)),
+            None => Checksum::parse(digest),
+        }
+    }
+
+    /// Reads the checksum of the file `name` from the checksum file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P, name: &str) -> Result<Checksum> {
+        let path = path.as_ref();
+        let mut sums = String::new();
+
+        File::open(path).and_then(|mut file| file.read_to_string(&mut sums))
+            .map_err(|e| Error::InvalidChecksum(format!("can't read {}: {}", path.display(), e)))?;
+
+        Checksum::find(&sums, name)
+    }
+
+    /// Expected checksum of `image`: `spec` is either a digest, or the path
+    /// of a checksum file listing the image.
+    pub fn resolve(spec: &str, image: &str) -> Result<Checksum> {
+        match Checksum::parse(spec) {
+            Ok(checksum) => Ok(checksum),
+            Err(e) => {
+                if Path::new(spec).is_file() {
+                    Checksum::from_file(spec, image)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+/// Parses a line of a checksum file into file name, digest and algorithm.
+fn parse_line(line: &str) -> Option<(String, &str, Option<HashAlgorithm>)> {
+    let line = line.trim_end_matches('\r');
+
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    // BSD: "SHA256 (name) = digest"
+    if let Some(open) = line.find(" (") {
+        if let (Some(algorithm), Some(close)) = (HashAlgorithm::from_name(&line[..open]), line.rfind(") = ")) {
+            return Some((line[open + 2..close].to_owned(), line[close + 4..].trim(), Some(algorithm)));
+        }
+    }
+
+    // GNU: "digest  name" in text mode, "digest *name" in binary mode. A
+    // leading backslash means that "\\", "\n" and "\r" are escaped in the name.
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+
+    let space = line.find(' ')?;
+    let name = &line[space + 1..];
+    let name = name.strip_prefix(' ').or_else(|| name.strip_prefix('*')).unwrap_or(name);
+
+    let name = if escaped { unescape(name) } else { name.to_owned() };
+
+    Some((name, &line[..space], None))
+}
+
+/// Decodes the `\\`, `\n` and `\r` escapes of a GNU file name.
+fn unescape(name: &str) -> String {
+    let mut decoded = String::with_capacity(name.len());
+    let mut chars = name.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => decoded.push('\n'),
+            Some('r') => decoded.push('\r'),
+            Some(c) => decoded.push(c),
+            None => decoded.push('\\'),
+        }
+    }
+
+    decoded
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.to_string().to_lowercase(), hex(&self.digest))
+    }
+}
+
+/// Digest of an image file, computed as the file is read.
+///
+/// What the burn doesn't read, because it was seeked over or never reached,
+/// is read through the same open file, so that the digest is the one of the
+/// file burnt.
+pub struct FileHash {
+    file: Arc<File>,
+    /// `None` once finished
+    hasher: Option<Hasher>,
+    /// Number of bytes hashed, from the start of the file
+    hashed: u64,
+    digest: Option<Vec<u8>>,
+}
+
+impl FileHash {
+    pub fn new(file: Arc<File>, algorithm: HashAlgorithm) -> FileHash {
+        FileHash {
+            file,
+            hasher: Some(Hasher::new(algorithm)),
+            hashed: 0,
+            digest: None,
+        }
+    }
+
+    /// Hashes the next bytes of the file.
+    pub fn input(&mut self, data: &[u8]) {
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.input(data);
+            self.hashed += data.len() as u64;
+        }
+    }
+
+    /// Reads and hashes the next `count` bytes of the file, up to its end
+    /// when `None`. Returns the number of bytes hashed.
+    pub fn catch_up(&mut self, count: Option<u64>) -> io::Result<u64> {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut left = count.unwrap_or(u64::MAX);
+
+        while left > 0 {
+            let length = left.min(BUFFER_SIZE as u64) as usize;
+
+            match self.file.read_at(&mut buffer[..length], self.hashed)? {
+                0 => break,
+                n => {
+                    self.input(&buffer[..n]);
+                    left -= n as u64;
+                }
+            }
+        }
+
+        Ok(count.unwrap_or(u64::MAX) - left)
+    }
+
+    /// Digest of the whole file, once done with reading it.
+    pub fn finish(&mut self) -> io::Result<Vec<u8>> {
+        if let Some(ref digest) = self.digest {
+            return Ok(digest.clone());
+        }
+
+        self.catch_up(None)?;
+
+        let digest = match self.hasher.take() {
+            Some(hasher) => hasher.result(),
+            None => return Err(io::Error::other("image hash already finished")),
+        };
+        self.digest = Some(digest.clone());
+
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    const MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+    const CRC32: &str = "d87f7e0c";
+
+    fn sha256() -> Checksum {
+        Checksum { algorithm: HashAlgorithm::Sha256, digest: unhex(SHA256).unwrap() }
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(unhex("00fFa5"), Some(vec![0x00, 0xff, 0xa5]));
+        assert_eq!(hex(&[0x00, 0xff, 0xa5]), "00ffa5");
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(unhex("é0"), None);
+    }
+
+    #[test]
+    fn algorithms_are_guessed_from_the_length() {
+        assert_eq!(Checksum::parse(SHA256).unwrap(), sha256());
+        assert_eq!(Checksum::parse(MD5).unwrap().algorithm, HashAlgorithm::Md5);
+        assert_eq!(Checksum::parse(CRC32).unwrap().algorithm, HashAlgorithm::Crc32);
+        assert_eq!(Checksum::parse(&SHA256.repeat(2)).unwrap().algorithm, HashAlgorithm::Sha512);
+
+        // BLAKE2b-512 is as long as SHA-512, and needs its prefix
+        let blake2b = Checksum::parse(&format!("blake2b:{}", SHA256.repeat(2))).unwrap();
+        assert_eq!(blake2b.algorithm, HashAlgorithm::Blake2b);
+
+        assert!(Checksum::parse(&SHA256[..60]).is_err());
+        assert!(Checksum::parse(&format!("md5:{}", SHA256)).is_err());
+    }
+
+    #[test]
+    fn gnu_lines_are_found_in_both_modes() {
+        let sums = format!("{}  other.img\n{} *image.img\n{}  text.img\n", MD5, SHA256, CRC32);
+
+        assert_eq!(Checksum::find(&sums, "image.img").unwrap(), sha256());
+        assert_eq!(Checksum::find(&sums, "text.img").unwrap().algorithm, HashAlgorithm::Crc32);
+        assert!(Checksum::find(&sums, "missing.img").is_err());
+    }
+
+    #[test]
+    fn bsd_lines_name_their_algorithm() {
+        let sums = format!("# comment\r\nSHA256 (image.img) = {}\r\nMD5 (a (b).img) = {}\r\n", SHA256, MD5);
+
+        assert_eq!(Checksum::find(&sums, "image.img").unwrap(), sha256());
+        assert_eq!(Checksum::find(&sums, "a (b).img").unwrap().algorithm, HashAlgorithm::Md5);
+    }
+
+    #[test]
+    fn escaped_names_are_decoded() {
+        let sums = format!("\\{}  dir\\\\new\\nline.img\n\\{}  carriage\\rreturn.img\n", SHA256, MD5);
+
+        assert_eq!(Checksum::find(&sums, "dir\\new\nline.img").unwrap(), sha256());
+        assert_eq!(Checksum::find(&sums, "carriage\rreturn.img").unwrap().algorithm, HashAlgorithm::Md5);
+    }
+
+    #[test]
+    fn file_names_match_when_paths_differ() {
+        let sums = format!("{}  ./out/image.img\n{}  image.img.xz\n", SHA256, MD5);
+
+        assert_eq!(Checksum::find(&sums, "/tmp/image.img").unwrap(), sha256());
+        assert_eq!(Checksum::find(&sums, "image.img.xz").unwrap().algorithm, HashAlgorithm::Md5);
+    }
+}
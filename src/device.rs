@@ -0,0 +1,726 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Device discovery and description.
+
+use std::fmt;
+use std::fs::{metadata,read_dir,read_link,File,OpenOptions};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+
+use blockdev;
+use error::{Error,Result};
+
+/// Size of the sectors used by the `size` attribute, whatever the device.
+const SYSFS_SECTOR: u64 = 512;
+
+/// Bus the device is attached to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    /// USB mass storage (sticks, external disks)
+    Usb,
+    /// USB card reader
+    SdReader,
+    /// Native SD/MMC controller
+    Mmc,
+    /// SATA/PATA
+    Ata,
+    /// NVMe
+    Nvme,
+    /// Anything else
+    Unknown,
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Bus::Usb => "USB",
+            Bus::SdReader => "SD reader",
+            Bus::Mmc => "MMC",
+            Bus::Ata => "ATA",
+            Bus::Nvme => "NVMe",
+            Bus::Unknown => "unknown",
+        })
+    }
+}
+
+/// Device representation
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// Convenient name
+    pub name: String,
+    /// Canonical path
+    pub path: String,
+    /// Size in MiB
+    pub mbytes: u64,
+    /// Exact size in bytes
+    pub bytes: u64,
+    /// Manufacturer, as reported by the device
+    pub vendor: Option<String>,
+    /// Model, as reported by the device
+    pub model: Option<String>,
+    /// Serial number
+    pub serial: Option<String>,
+    /// Bus the device is attached to
+    pub bus: Bus,
+    /// Whether the media can be removed
+    pub removable: bool,
+    /// Whether the device is write-protected
+    pub read_only: bool,
+    /// Smallest addressable unit, in bytes
+    pub logical_sector_size: u64,
+    /// Smallest unit the device writes atomically, in bytes
+    pub physical_sector_size: u64,
+}
+
+/// Formats as "SanDisk Ultra 32 GB (USB, removable)".
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.vendor, &self.model) {
+            (Some(vendor), Some(model)) => write!(f, "{} {}", vendor, model)?,
+            (Some(label), None) | (None, Some(label)) => write!(f, "{}", label)?,
+            (None, None) => write!(f, "{}", self.name)?,
+        }
+
+        write!(f, " {} GB ({}", (self.bytes + 500_000_000) / 1_000_000_000, self.bus)?;
+
+        if self.removable {
+            write!(f, ", removable")?;
+        }
+
+        if self.read_only {
+            write!(f, ", read-only")?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+/// Attributes of a block device, as exposed by sysfs
+#[derive(Clone, Debug, Default)]
+pub struct BlockInfo {
+    pub bytes: u64,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub bus: Option<Bus>,
+    pub removable: bool,
+    pub read_only: bool,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+}
+
+/// Access to a sysfs tree.
+///
+/// The root defaults to `/sys` and can point to any directory mimicking it,
+/// as can the directory of the `/dev/disk/by-id` links naming the devices.
+#[derive(Clone, Debug)]
+pub struct Sysfs {
+    root: PathBuf,
+    by_id: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Sysfs {
+        Sysfs::new("/sys")
+    }
+}
+
+impl Sysfs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Sysfs {
+        Sysfs::with_by_id(root, "/dev/disk/by-id")
+    }
+
+    /// Reads the device links from `by_id` instead of `/dev/disk/by-id`.
+    pub fn with_by_id<P: Into<PathBuf>, Q: Into<PathBuf>>(root: P, by_id: Q) -> Sysfs {
+        Sysfs {
+            root: root.into(),
+            by_id: by_id.into(),
+        }
+    }
+
+    /// Root of the sysfs tree.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Reads the attributes of the block device `name` (e.g. "sdb", "mmcblk0").
+    pub fn block_info(&self, name: &str) -> Result<BlockInfo> {
+        let block = self.root.join("block").join(name);
+
+        if !block.exists() {
+            return Err(Error::DeviceNotFound(name.to_owned()));
+        }
+
+        let sectors: u64 = read_number(&block.join("size")).unwrap_or(0);
+        let logical = read_number(&block.join("queue/logical_block_size")).unwrap_or(SYSFS_SECTOR);
+        let physical = read_number(&block.join("queue/physical_block_size")).unwrap_or(logical);
+
+        let mut info = BlockInfo {
+            bytes: sectors * SYSFS_SECTOR,
+            removable: read_number(&block.join("removable")).unwrap_or(0) != 0,
+            read_only: read_number(&block.join("ro")).unwrap_or(0) != 0,
+            logical_sector_size: logical,
+            physical_sector_size: physical,
+            ..BlockInfo::default()
+        };
+
+        let device = block.join("device");
+
+        info.vendor = read_attr(&device.join("vendor"));
+        // SCSI exposes "model", MMC cards "name"
+        info.model = read_attr(&device.join("model")).or_else(|| read_attr(&device.join("name")));
+
+        if let Ok(device) = device.canonicalize() {
+            // Walk up the device chain until reaching something telling the bus
+            for dir in device.ancestors().take_while(|dir| dir.starts_with(&self.root)) {
+                if info.serial.is_none() {
+                    info.serial = read_attr(&dir.join("serial"));
+                }
+
+                let name = dir.file_name().map(|name| name.to_string_lossy().into_owned());
+
+                match subsystem(dir).as_deref() {
+                    // Interfaces are "usb" too, but only the device has ids
+                    Some("usb") if dir.join("idVendor").exists() => {
+                        info.vendor = info.vendor.or_else(|| read_attr(&dir.join("manufacturer")));
+                        info.model = info.model.or_else(|| read_attr(&dir.join("product")));
+                        info.bus = Some(Bus::Usb);
+                    }
+                    Some("mmc") => info.bus = Some(Bus::Mmc),
+                    Some("nvme") => info.bus = Some(Bus::Nvme),
+                    _ if name.is_some_and(|name| is_ata_port(&name)) => info.bus = Some(Bus::Ata),
+                    _ => {}
+                }
+
+                if info.bus.is_some() {
+                    break;
+                }
+            }
+        }
+
+        // Some readers sit behind a USB-to-ATA bridge
+        if (info.bus == Some(Bus::Usb) || info.bus == Some(Bus::Ata)) && is_card_reader(&info) {
+            info.bus = Some(Bus::SdReader);
+        }
+
+        Ok(info)
+    }
+
+    /// Directory of the USB device holding the block device `name`, if any.
+    fn usb_device(&self, name: &str) -> Option<PathBuf> {
+        let device = self.root.join("block").join(name).join("device").canonicalize().ok()?;
+
+        device.ancestors().take_while(|dir| dir.starts_with(&self.root))
+            .find(|dir| subsystem(dir).as_deref() == Some("usb") && dir.join("idVendor").exists())
+            .map(Path::to_path_buf)
+    }
+
+    /// Powers off the USB device holding the block device `name`, as if it
+    /// had been unplugged.
+    pub fn eject(&self, name: &str) -> Result<()> {
+        let usb = self.usb_device(name).ok_or_else(|| {
+            Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't a USB device", name)))
+        })?;
+
+        OpenOptions::new().write(true).open(usb.join("remove"))?.write_all(b"1")?;
+
+        Ok(())
+    }
+
+    /// Lists the USB and MMC devices, as `devices()` with the default filter.
+    pub fn device_list(&self) -> Result<Vec<Device>> {
+        self.devices(&DeviceFilter::default())
+    }
+
+    /// Lists the disks found in `block/` matching `filter`.
+    ///
+    /// Partitions are skipped, as are devices whose attributes can't be read.
+    pub fn devices(&self, filter: &DeviceFilter) -> Result<Vec<Device>> {
+        let mut devices = Vec::new();
+
+        for entry in read_dir(self.root.join("block"))? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+
+            if entry.path().join("partition").exists() {
+                continue;
+            }
+
+            match self.device(&entry.file_name().to_string_lossy()) {
+                Ok(device) => if filter.matches(&device) {
+                    devices.push(device);
+                },
+                Err(_) => continue,
+            }
+        }
+
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(devices)
+    }
+
+    /// Describes the block device `name` (e.g. "sdb").
+    ///
+    /// The device is named after its `/dev/disk/by-id` link, or after `name`
+    /// when it has none (yet, udev creating it after the device appears).
+    pub fn device(&self, name: &str) -> Result<Device> {
+        let info = self.block_info(name)?;
+        let path = Path::new("/dev").join(name);
+
+        // Links are relative ("../../sdb"), only the name of their target matters
+        let link = read_dir(&self.by_id).ok().and_then(|entries| {
+            let mut links: Vec<_> = entries.filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    read_link(entry.path()).ok().as_ref().and_then(|target| target.file_name())
+                        == Some(name.as_ref())
+                })
+                .filter_map(|entry| id_name(&entry.file_name().to_string_lossy()))
+                .collect();
+
+            links.sort();
+            links.into_iter().next()
+        });
+
+        Ok(make_device(link.unwrap_or_else(|| name.to_owned()), path.to_string_lossy().into_owned(), info))
+    }
+}
+
+/// Which devices to list
+#[derive(Clone, Debug)]
+pub struct DeviceFilter {
+    /// Buses to accept
+    pub buses: Vec<Bus>,
+    /// Only accept devices whose media can be removed
+    pub removable_only: bool,
+    /// Smallest accepted size, in bytes
+    pub min_size: Option<u64>,
+    /// Largest accepted size, in bytes
+    pub max_size: Option<u64>,
+}
+
+/// Accepts USB and MMC devices, whatever their size.
+impl Default for DeviceFilter {
+    fn default() -> DeviceFilter {
+        DeviceFilter {
+            buses: vec![Bus::Usb, Bus::SdReader, Bus::Mmc],
+            removable_only: false,
+            min_size: None,
+            max_size: None,
+        }
+    }
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, device: &Device) -> bool {
+        self.buses.contains(&device.bus)
+            && (device.removable || !self.removable_only)
+            && self.min_size.is_none_or(|size| device.bytes >= size)
+            && self.max_size.is_none_or(|size| device.bytes <= size)
+    }
+}
+
+/// Name of the device behind a `/dev/disk/by-id` link: "usb-SanDisk_Ultra_..."
+/// gives "SanDisk".
+fn id_name(link: &str) -> Option<String> {
+    let mut parts = link.splitn(2, '-');
+
+    match parts.next()? {
+        "usb" | "mmc" | "ata" | "nvme" | "scsi" => {}
+        _ => return None,
+    }
+
+    parts.next()?.split('_').next().filter(|name| !name.is_empty()).map(str::to_owned)
+}
+
+fn make_device(name: String, path: String, info: BlockInfo) -> Device {
+    Device{
+        name,
+        path,
+        mbytes: info.bytes / (1024*1024),
+        bytes: info.bytes,
+        vendor: info.vendor,
+        model: info.model,
+        serial: info.serial,
+        bus: info.bus.unwrap_or(Bus::Unknown),
+        removable: info.removable,
+        read_only: info.read_only,
+        logical_sector_size: info.logical_sector_size,
+        physical_sector_size: info.physical_sector_size,
+    }
+}
+
+/// Card readers identify themselves through their model string.
+fn is_card_reader(info: &BlockInfo) -> bool {
+    info.model.iter().chain(info.vendor.iter()).any(|label| {
+        let label = label.to_lowercase();
+
+        label.contains("reader") || label.contains("sd/mmc") || label.contains("sdxc")
+    })
+}
+
+/// ATA ports show up as "ata1", "ata2"... in the device chain.
+fn is_ata_port(name: &str) -> bool {
+    name.starts_with("ata") && name.len() > 3 && name[3..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Name of the subsystem `dir` belongs to, if any.
+fn subsystem(dir: &Path) -> Option<String> {
+    read_link(dir.join("subsystem")).ok()
+        .and_then(|link| link.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+/// Reads a sysfs attribute, trimmed. Missing or blank attributes are `None`.
+fn read_attr(path: &Path) -> Option<String> {
+    let mut content = String::new();
+
+    File::open(path).and_then(|mut file| file.read_to_string(&mut content)).ok()?;
+
+    let content = content.trim();
+
+    if content.is_empty() {
+        None
+    } else {
+        Some(content.to_owned())
+    }
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    read_attr(path).and_then(|value| value.parse().ok())
+}
+
+/// Destination of a burn
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Block device, such as an SD card
+    Device(String),
+    /// Regular file receiving the image, created if needed
+    File(String),
+}
+
+impl Target {
+    /// Tells what `path` is.
+    ///
+    /// Regular files, existing or not, are refused unless `allow_file_targets`
+    /// is set, so that a mistyped device path doesn't silently end up as a file.
+    /// Anything else but a block device, such as a directory or a character
+    /// device, is refused.
+    pub fn resolve(path: &str, allow_file_targets: bool) -> Result<Target> {
+        match metadata(path) {
+            Ok(ref meta) if blockdev::is_block(meta) => Ok(Target::Device(path.to_owned())),
+            Ok(ref meta) if meta.is_file() && allow_file_targets => Ok(Target::File(path.to_owned())),
+            Ok(_) => Err(Error::NotADevice(path.to_owned())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && allow_file_targets => {
+                Ok(Target::File(path.to_owned()))
+            }
+            Err(e) => Err(Error::device_open(path, e)),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match *self {
+            Target::Device(ref path) | Target::File(ref path) => path,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(*self, Target::File(_))
+    }
+}
+
+/// Retrieves the target designated by a device's name or path.
+///
+/// Anything but a listed device is refused (`None`), unless
+/// `allow_file_targets` is set and `input` is a regular file or doesn't
+/// exist yet.
+pub fn device_path(devices: &[Device], input: &str, allow_file_targets: bool) -> Result<Option<Target>> {
+    for device in devices.iter() {
+        if input == device.name {
+            return Ok(Some(Target::Device(device.path.clone())));
+        }
+    }
+
+    let path = match Path::new(input).canonicalize() {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && allow_file_targets => {
+            return Ok(Some(Target::File(input.to_owned())));
+        }
+        Err(e) => return Err(Error::device_open(input, e)),
+    };
+
+    for device in devices.iter() {
+        if path == device.path {
+            return Ok(Some(Target::Device(path)));
+        }
+    }
+
+    match Target::resolve(&path, allow_file_targets) {
+        Ok(target @ Target::File(_)) => Ok(Some(target)),
+        _ => Ok(None),
+    }
+}
+
+/// Get the list of available USB and MMC devices.
+#[cfg(target_os = "linux")]
+pub fn get_device_list() -> Result<Vec<Device>> {
+    Sysfs::default().device_list()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs::{create_dir_all,remove_dir_all,write};
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use error::Error;
+
+    use super::*;
+
+    const USB_STICK: &str = "pci0000:00/0000:00:14.0/usb1/1-1/1-1:1.0/host0/target0:0:0/0:0:0:0";
+    const MMC_CARD: &str = "platform/fe300000.mmc/mmc_host/mmc0/mmc0:aaaa";
+    const ATA_READER: &str = "pci0000:00/0000:00:17.0/ata2/host1/target1:0:0/1:0:0:0";
+    const NVME_DISK: &str = "pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0";
+
+    /// Writes the sysfs attribute `name` of `dir`.
+    fn attr(dir: &Path, name: &str, value: &str) {
+        create_dir_all(dir).unwrap();
+        write(dir.join(name), format!("{}\n", value)).unwrap();
+    }
+
+    /// Attaches `dir` to the subsystem `kind/name`, as in "bus/usb".
+    fn subsystem(sys: &Path, dir: &Path, kind: &str) {
+        create_dir_all(sys.join(kind)).unwrap();
+        symlink(sys.join(kind), dir.join("subsystem")).unwrap();
+    }
+
+    /// Adds the disk `name` of `sectors` sectors under the device at `device`,
+    /// returning its block directory.
+    fn add_disk(sys: &Path, device: &str, name: &str, sectors: u64) -> PathBuf {
+        let device = sys.join("devices").join(device);
+        let block = device.join("block").join(name);
+
+        attr(&block, "size", &sectors.to_string());
+        symlink(&block, sys.join("block").join(name)).unwrap();
+        symlink(&device, block.join("device")).unwrap();
+
+        block
+    }
+
+    /// Adds a `by-id` link named `id` pointing to the device `name`.
+    fn add_link(by_id: &Path, id: &str, name: &str) {
+        symlink(Path::new("../..").join(name), by_id.join(id)).unwrap();
+    }
+
+    /// Fake `/sys` and `/dev/disk/by-id` holding a USB stick, an SD card, an
+    /// SD reader behind an ATA bridge and an NVMe disk.
+    pub fn fixture(name: &str) -> Sysfs {
+        let root = env::temp_dir().join(format!("acetylene-device-{}-{}", name, process::id()));
+        let sys = root.join("sys");
+        let by_id = root.join("dev/disk/by-id");
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(sys.join("block")).unwrap();
+        create_dir_all(&by_id).unwrap();
+
+        // USB stick, 1 GiB, with a partition
+        let sdb = add_disk(&sys, USB_STICK, "sdb", 2 * 1024 * 1024);
+        attr(&sdb, "removable", "1");
+        let usb = sys.join("devices/pci0000:00/0000:00:14.0/usb1/1-1");
+        attr(&usb, "idVendor", "0781");
+        attr(&usb, "manufacturer", "SanDisk");
+        attr(&usb, "product", "Cruzer Blade");
+        attr(&usb, "serial", "4C530001");
+        subsystem(&sys, &usb, "bus/usb");
+        subsystem(&sys, &usb.join("1-1:1.0"), "bus/usb");
+        let sdb1 = sdb.join("sdb1");
+        attr(&sdb1, "partition", "1");
+        attr(&sdb1, "size", "2048");
+        symlink(&sdb1, sys.join("block/sdb1")).unwrap();
+        add_link(&by_id, "usb-SanDisk_Cruzer_Blade_4C530001-0:0", "sdb");
+        add_link(&by_id, "usb-SanDisk_Cruzer_Blade_4C530001-0:0-part1", "sdb1");
+
+        // SD card on a native controller, 16 GiB
+        let mmcblk0 = add_disk(&sys, MMC_CARD, "mmcblk0", 32 * 1024 * 1024);
+        let card = sys.join("devices").join(MMC_CARD);
+        attr(&card, "name", "SC16G");
+        attr(&card, "serial", "0x1234abcd");
+        subsystem(&sys, &card, "bus/mmc");
+        attr(&mmcblk0, "ro", "1");
+        add_link(&by_id, "mmc-SC16G_0x1234abcd", "mmcblk0");
+
+        // SD reader behind a USB-to-ATA bridge, 4 GiB, with an id ending in "part"
+        let sdc = add_disk(&sys, ATA_READER, "sdc", 8 * 1024 * 1024);
+        attr(&sdc, "removable", "1");
+        attr(&sys.join("devices").join(ATA_READER), "model", "Multi-Card Reader");
+        add_link(&by_id, "usb-Generic_Counterpart", "sdc");
+        add_link(&by_id, "wwn-0x5000000000000001", "sdc");
+
+        // NVMe disk, 256 GiB
+        let nvme0n1 = add_disk(&sys, NVME_DISK, "nvme0n1", 512 * 1024 * 1024);
+        let controller = sys.join("devices").join(NVME_DISK);
+        attr(&controller, "model", "Samsung SSD 970");
+        attr(&controller, "serial", "S4EWNX0N");
+        subsystem(&sys, &controller, "class/nvme");
+        attr(&nvme0n1.join("queue"), "logical_block_size", "512");
+        attr(&nvme0n1.join("queue"), "physical_block_size", "4096");
+
+        Sysfs::with_by_id(sys, by_id)
+    }
+
+    fn names(devices: &[Device]) -> Vec<&str> {
+        devices.iter().map(|device| device.name.as_str()).collect()
+    }
+
+    #[test]
+    fn devices_are_described_from_their_chain() {
+        let sysfs = fixture("chain");
+
+        let stick = sysfs.device("sdb").unwrap();
+        assert_eq!(stick.name, "SanDisk");
+        assert_eq!(stick.path, "/dev/sdb");
+        assert_eq!(stick.bus, Bus::Usb);
+        assert_eq!(stick.bytes, 1 << 30);
+        assert_eq!(stick.mbytes, 1024);
+        assert_eq!(stick.vendor.as_deref(), Some("SanDisk"));
+        assert_eq!(stick.model.as_deref(), Some("Cruzer Blade"));
+        assert_eq!(stick.serial.as_deref(), Some("4C530001"));
+        assert!(stick.removable);
+
+        let card = sysfs.device("mmcblk0").unwrap();
+        assert_eq!(card.name, "SC16G");
+        assert_eq!(card.bus, Bus::Mmc);
+        assert_eq!(card.model.as_deref(), Some("SC16G"));
+        assert!(card.read_only);
+
+        let reader = sysfs.device("sdc").unwrap();
+        assert_eq!(reader.name, "Generic");
+        assert_eq!(reader.bus, Bus::SdReader);
+
+        let disk = sysfs.device("nvme0n1").unwrap();
+        assert_eq!(disk.name, "nvme0n1");
+        assert_eq!(disk.bus, Bus::Nvme);
+        assert_eq!(disk.physical_sector_size, 4096);
+    }
+
+    #[test]
+    fn block_info_reads_the_attributes_up_the_chain() {
+        let sysfs = fixture("block-info");
+
+        let info = sysfs.block_info("sdc").unwrap();
+        assert_eq!(info.bytes, 8 * 1024 * 1024 * 512);
+        assert_eq!(info.vendor, None);
+        assert_eq!(info.model.as_deref(), Some("Multi-Card Reader"));
+        assert!(info.removable);
+        assert!(!info.read_only);
+
+        // The serial is the one of the closest device having one
+        let info = sysfs.block_info("sdb").unwrap();
+        assert_eq!(info.vendor.as_deref(), Some("SanDisk"));
+        assert_eq!(info.serial.as_deref(), Some("4C530001"));
+        assert_eq!(info.bus, Some(Bus::Usb));
+
+        let info = sysfs.block_info("mmcblk0").unwrap();
+        assert_eq!(info.serial.as_deref(), Some("0x1234abcd"));
+        assert!(info.read_only);
+        assert!(!info.removable);
+
+        let info = sysfs.block_info("nvme0n1").unwrap();
+        assert_eq!(info.model.as_deref(), Some("Samsung SSD 970"));
+        assert_eq!(info.serial.as_deref(), Some("S4EWNX0N"));
+        assert_eq!(info.logical_sector_size, 512);
+        assert_eq!(info.physical_sector_size, 4096);
+    }
+
+    #[test]
+    fn block_info_defaults_the_sector_sizes() {
+        let sysfs = fixture("sector-sizes");
+        let queue = sysfs.root().join("block/sdb/queue");
+
+        let info = sysfs.block_info("sdb").unwrap();
+        assert_eq!(info.logical_sector_size, 512);
+        assert_eq!(info.physical_sector_size, 512);
+
+        // The physical size falls back to the logical one
+        attr(&queue, "logical_block_size", "4096");
+        let info = sysfs.block_info("sdb").unwrap();
+        assert_eq!(info.logical_sector_size, 4096);
+        assert_eq!(info.physical_sector_size, 4096);
+
+        assert!(matches!(sysfs.block_info("sdz"), Err(Error::DeviceNotFound(_))));
+    }
+
+    #[test]
+    fn partitions_are_not_listed() {
+        let sysfs = fixture("partitions");
+        let filter = DeviceFilter {
+            buses: vec![Bus::Usb, Bus::SdReader, Bus::Mmc, Bus::Nvme],
+            ..DeviceFilter::default()
+        };
+
+        let devices = sysfs.devices(&filter).unwrap();
+        let paths: Vec<&str> = devices.iter().map(|device| device.path.as_str()).collect();
+
+        assert_eq!(paths, ["/dev/mmcblk0", "/dev/nvme0n1", "/dev/sdb", "/dev/sdc"]);
+    }
+
+    #[test]
+    fn filters_bound_bus_removable_and_size() {
+        let sysfs = fixture("filters");
+
+        assert_eq!(names(&sysfs.device_list().unwrap()), ["SC16G", "SanDisk", "Generic"]);
+
+        let nvme_only = DeviceFilter { buses: vec![Bus::Nvme], ..DeviceFilter::default() };
+        assert_eq!(names(&sysfs.devices(&nvme_only).unwrap()), ["nvme0n1"]);
+
+        let removable = DeviceFilter { removable_only: true, ..DeviceFilter::default() };
+        assert_eq!(names(&sysfs.devices(&removable).unwrap()), ["SanDisk", "Generic"]);
+
+        // Bounds are inclusive
+        let sized = DeviceFilter {
+            min_size: Some(1 << 30),
+            max_size: Some(4 << 30),
+            ..DeviceFilter::default()
+        };
+        assert_eq!(names(&sysfs.devices(&sized).unwrap()), ["SanDisk", "Generic"]);
+
+        let small = DeviceFilter { max_size: Some((1 << 30) - 1), ..DeviceFilter::default() };
+        assert!(sysfs.devices(&small).unwrap().is_empty());
+    }
+
+    #[test]
+    fn directories_and_character_devices_are_not_targets() {
+        let directory = env::temp_dir();
+        let directory = directory.to_str().unwrap();
+
+        assert!(matches!(Target::resolve(directory, true), Err(Error::NotADevice(_))));
+        assert!(matches!(Target::resolve("/dev/null", true), Err(Error::NotADevice(_))));
+    }
+
+    #[test]
+    fn regular_files_are_only_targets_when_allowed() {
+        let exe = env::current_exe().unwrap();
+        let exe = exe.to_str().unwrap();
+        let missing = env::temp_dir().join("acetylene-missing.img");
+        let missing = missing.to_str().unwrap();
+
+        assert_eq!(Target::resolve(exe, true).unwrap(), Target::File(exe.to_owned()));
+        assert!(matches!(Target::resolve(exe, false), Err(Error::NotADevice(_))));
+        assert_eq!(Target::resolve(missing, true).unwrap(), Target::File(missing.to_owned()));
+        assert!(Target::resolve(missing, false).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Errors reported by acetylene.
+
+use std::fmt;
+use std::io;
+use std::error;
+use std::result;
+
+use libc;
+
+/// Convenient alias for results returned by acetylene.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Everything that can go wrong while looking for devices or burning images.
+#[derive(Debug)]
+pub enum Error {
+    /// The source image doesn't exist
+    ImageNotFound(String),
+    /// The image archive is corrupted or unsupported
+    InvalidArchive(String),
+    /// The requested entry isn't in the image archive
+    EntryNotFound(String),
+    /// The image archive holds several candidate images, one must be named
+    AmbiguousArchive(Vec<String>),
+    /// The block map is malformed
+    InvalidBmap(String),
+    /// The image data doesn't match the checksum of its block map
+    BmapChecksum {
+        /// Offset of the mismatching range
+        offset: u64,
+    },
+    /// The Android sparse image is malformed
+    InvalidSparse(String),
+    /// The Android sparse image doesn't match its CRC32
+    SparseChecksum {
+        /// Offset in the expanded image where the check failed
+        offset: u64,
+    },
+    /// The expected checksum is malformed, or missing from the checksum file
+    InvalidChecksum(String),
+    /// The image file doesn't match its expected checksum
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+        /// Device the image was already written to, whose content is invalid
+        device: Option<String>,
+    },
+    /// The image, or its checksum file, isn't signed by a trusted key
+    BadSignature(String),
+    /// The partition table is malformed
+    InvalidPartitionTable(String),
+    /// The filesystem of the expanded partition couldn't be grown
+    GrowFilesystem(String),
+    /// The target device doesn't exist
+    DeviceNotFound(String),
+    /// The target device is used by someone else
+    DeviceBusy(String),
+    /// The target isn't a block device, nor an allowed regular file
+    NotADevice(String),
+    /// The target device holds the running system
+    SystemDisk(String),
+    /// Filesystems or swaps of the target device are in use
+    DeviceInUse {
+        device: String,
+        mount_points: Vec<String>,
+    },
+    /// A filesystem or swap of the target device couldn't be released
+    Unmount {
+        path: String,
+        cause: io::Error,
+    },
+    /// Insufficient rights to open the given path
+    PermissionDenied(String),
+    /// The image doesn't fit on the device
+    ImageTooLarge {
+        image: u64,
+        device: u64,
+    },
+    /// The device accepted less bytes than requested
+    ShortWrite {
+        offset: u64,
+        expected: usize,
+        written: usize,
+    },
+    /// Failure while reading the source image
+    Read {
+        offset: u64,
+        cause: io::Error,
+    },
+    /// Failure while writing to the device
+    Write {
+        offset: u64,
+        cause: io::Error,
+    },
+    /// The data read back from the device differs from the image
+    VerifyMismatch {
+        /// Offset of the first differing block
+        offset: u64,
+    },
+    /// The burn was cancelled, or the progress receiver dropped
+    Cancelled,
+    /// Any other I/O failure
+    Io(io::Error),
+}
+
+impl Error {
+    /// Builds an error from a failure to open the image at `path`.
+    pub fn image_open(path: &str, cause: io::Error) -> Error {
+        match cause.kind() {
+            io::ErrorKind::NotFound => Error::ImageNotFound(path.to_owned()),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_owned()),
+            _ => Error::Io(cause),
+        }
+    }
+
+    /// Builds an error from a failure to open the device at `path`.
+    pub fn device_open(path: &str, cause: io::Error) -> Error {
+        if cause.raw_os_error() == Some(libc::EBUSY) {
+            return Error::DeviceBusy(path.to_owned());
+        }
+
+        match cause.kind() {
+            io::ErrorKind::NotFound => Error::DeviceNotFound(path.to_owned()),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_owned()),
+            _ => Error::Io(cause),
+        }
+    }
+}
+
+/// `io::Error` isn't `Clone`, so I/O causes are rebuilt from their kind and message.
+fn clone_io(error: &io::Error) -> io::Error {
+    match error.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(error.kind(), error.to_string()),
+    }
+}
+
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match *self {
+            Error::ImageNotFound(ref path) => Error::ImageNotFound(path.clone()),
+            Error::InvalidArchive(ref reason) => Error::InvalidArchive(reason.clone()),
+            Error::EntryNotFound(ref name) => Error::EntryNotFound(name.clone()),
+            Error::AmbiguousArchive(ref names) => Error::AmbiguousArchive(names.clone()),
+            Error::InvalidBmap(ref reason) => Error::InvalidBmap(reason.clone()),
+            Error::BmapChecksum { offset } => Error::BmapChecksum { offset },
+            Error::InvalidSparse(ref reason) => Error::InvalidSparse(reason.clone()),
+            Error::SparseChecksum { offset } => Error::SparseChecksum { offset },
+            Error::InvalidChecksum(ref reason) => Error::InvalidChecksum(reason.clone()),
+            Error::ChecksumMismatch { ref expected, ref actual, ref device } => Error::ChecksumMismatch {
+                expected: expected.clone(),
+                actual: actual.clone(),
+                device: device.clone(),
+            },
+            Error::BadSignature(ref reason) => Error::BadSignature(reason.clone()),
+            Error::InvalidPartitionTable(ref reason) => Error::InvalidPartitionTable(reason.clone()),
+            Error::GrowFilesystem(ref reason) => Error::GrowFilesystem(reason.clone()),
+            Error::DeviceNotFound(ref path) => Error::DeviceNotFound(path.clone()),
+            Error::DeviceBusy(ref path) => Error::DeviceBusy(path.clone()),
+            Error::NotADevice(ref path) => Error::NotADevice(path.clone()),
+            Error::SystemDisk(ref path) => Error::SystemDisk(path.clone()),
+            Error::DeviceInUse { ref device, ref mount_points } => Error::DeviceInUse {
+                device: device.clone(),
+                mount_points: mount_points.clone(),
+            },
+            Error::Unmount { ref path, ref cause } => Error::Unmount { path: path.clone(), cause: clone_io(cause) },
+            Error::PermissionDenied(ref path) => Error::PermissionDenied(path.clone()),
+            Error::ImageTooLarge { image, device } => Error::ImageTooLarge { image, device },
+            Error::ShortWrite { offset, expected, written } => Error::ShortWrite { offset, expected, written },
+            Error::Read { offset, ref cause } => Error::Read { offset, cause: clone_io(cause) },
+            Error::Write { offset, ref cause } => Error::Write { offset, cause: clone_io(cause) },
+            Error::VerifyMismatch { offset } => Error::VerifyMismatch { offset },
+            Error::Cancelled => Error::Cancelled,
+            Error::Io(ref cause) => Error::Io(clone_io(cause)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ImageNotFound(ref path) => write!(f, "image {} not found", path),
+            Error::InvalidArchive(ref reason) => write!(f, "invalid archive: {}", reason),
+            Error::EntryNotFound(ref name) => write!(f, "entry {} not found in archive", name),
+            Error::AmbiguousArchive(ref names) => write!(
+                f, "archive holds several candidate images: {}", names.join(", ")
+            ),
+            Error::InvalidBmap(ref reason) => write!(f, "invalid block map: {}", reason),
+            Error::BmapChecksum { offset } => write!(
+                f, "image data at offset {} doesn't match its block map checksum", offset
+            ),
+            Error::InvalidSparse(ref reason) => write!(f, "invalid sparse image: {}", reason),
+            Error::SparseChecksum { offset } => write!(f, "sparse image CRC32 mismatch at offset {}", offset),
+            Error::InvalidChecksum(ref reason) => write!(f, "invalid checksum: {}", reason),
+            Error::ChecksumMismatch { ref expected, ref actual, device: None } => write!(
+                f, "image is corrupt: expected checksum {}, got {}", expected, actual
+            ),
+            Error::ChecksumMismatch { ref expected, ref actual, device: Some(ref device) } => write!(
+                f, "image is corrupt: expected checksum {}, got {}; it was written to {}, whose content is invalid",
+                expected, actual, device
+            ),
+            Error::BadSignature(ref reason) => write!(f, "signature check failed: {}", reason),
+            Error::InvalidPartitionTable(ref reason) => write!(f, "invalid partition table: {}", reason),
+            Error::GrowFilesystem(ref reason) => write!(f, "can't grow the filesystem: {}", reason),
+            Error::DeviceNotFound(ref path) => write!(f, "device {} not found", path),
+            Error::DeviceBusy(ref path) => write!(f, "device {} is busy", path),
+            Error::NotADevice(ref path) => write!(f, "{} isn't a block device", path),
+            Error::SystemDisk(ref path) => write!(f, "device {} holds the running system", path),
+            Error::DeviceInUse { ref device, ref mount_points } => write!(
+                f, "device {} is in use by {}", device, mount_points.join(", ")
+            ),
+            Error::Unmount { ref path, ref cause } => write!(f, "can't release {}: {}", path, cause),
+            Error::PermissionDenied(ref path) => write!(f, "permission denied on {}", path),
+            Error::ImageTooLarge { image, device } => write!(
+                f, "image of {} bytes doesn't fit on a device of {} bytes", image, device
+            ),
+            Error::ShortWrite { offset, expected, written } => write!(
+                f, "short write at offset {}: {} of {} bytes written", offset, written, expected
+            ),
+            Error::Read { offset, ref cause } => write!(f, "read failed at offset {}: {}", offset, cause),
+            Error::Write { offset, ref cause } => write!(f, "write failed at offset {}: {}", offset, cause),
+            Error::VerifyMismatch { offset } => write!(f, "verification failed at offset {}", offset),
+            Error::Cancelled => write!(f, "burn cancelled"),
+            Error::Io(ref cause) => write!(f, "I/O error: {}", cause),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Read { ref cause, .. } => Some(cause),
+            Error::Write { ref cause, .. } => Some(cause),
+            Error::Unmount { ref cause, .. } => Some(cause),
+            Error::Io(ref cause) => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
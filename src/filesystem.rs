@@ -0,0 +1,390 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Growth of the filesystem of an expanded partition.
+//!
+//! FAT is grown in place, but only as far as its allocation tables reach:
+//! they are sized when formatting, and the data right after them can't be
+//! moved. ext2/3/4 are handed to e2fsck and resize2fs, from e2fsprogs,
+//! which open a filesystem at an offset into the disk.
+
+use std::fs::{File,OpenOptions};
+use std::io::{Read,Seek,Write};
+use std::process::{Command,Stdio};
+
+use blockdev;
+use error::{Error,Result};
+use partitions::{Partition,le,put_le,read_at,write_at};
+
+const EXT_SUPERBLOCK: usize = 1024;
+const EXT_MAGIC: u64 = 0xef53;
+
+/// Largest cluster counts of FAT12, FAT16 and FAT32
+const FAT12_CLUSTERS: u64 = 4084;
+const FAT16_CLUSTERS: u64 = 65524;
+const FAT32_CLUSTERS: u64 = 0x0fff_fff4;
+
+fn failed(reason: String) -> Error {
+    Error::GrowFilesystem(reason)
+}
+
+/// Grows the ext2/3/4 or FAT filesystem of `partition`, on the device at `path`.
+///
+/// Returns `false` when the filesystem is of another kind, or when a FAT
+/// has no room left in its allocation tables.
+pub fn grow_filesystem(path: &str, partition: &Partition) -> Result<bool> {
+    let mut device = OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| Error::device_open(path, e))?;
+    let mut head = [0u8; 2048];
+
+    if read_at(&mut device, partition.start, &mut head)? < head.len() {
+        return Ok(false);
+    }
+
+    if le(&head[EXT_SUPERBLOCK + 56..EXT_SUPERBLOCK + 58]) == EXT_MAGIC {
+        grow_ext(&mut device, path, partition)?;
+
+        return Ok(true);
+    }
+
+    if is_fat(&head[..512]) {
+        let grown = grow_fat(&mut device, partition, &head[..512])?;
+        device.sync_data()?;
+
+        return Ok(grown);
+    }
+
+    Ok(false)
+}
+
+fn grow_ext(device: &mut File, path: &str, partition: &Partition) -> Result<()> {
+    let filesystem = format!("{}?offset={}", path, partition.start);
+
+    // resize2fs cuts image files to the size of the filesystem, regardless
+    // of the offset: what follows the partition, like a backup GPT, is kept
+    // aside to be put back
+    let length = device.metadata()?.len();
+    let mut tail = vec![];
+
+    if !blockdev::is_block_device(device) && length > partition.end() {
+        tail.resize((length - partition.end()) as usize, 0);
+        read_at(device, partition.end(), &mut tail)?;
+    }
+
+    // resize2fs wants a freshly checked filesystem, 1 means errors were fixed
+    run("e2fsck", &["-f", "-p", &filesystem], &[0, 1])?;
+    let resized = run("resize2fs", &[&filesystem, &format!("{}K", partition.size / 1024)], &[0]);
+
+    if !tail.is_empty() {
+        device.set_len(length)?;
+        write_at(device, partition.end(), &tail)?;
+        device.sync_data()?;
+    }
+
+    resized
+}
+
+fn run(program: &str, args: &[&str], success: &[i32]) -> Result<()> {
+    let output = Command::new(program).args(args).stdin(Stdio::null()).output()
+        .map_err(|e| failed(format!("can't run {}: {}", program, e)))?;
+
+    if output.status.code().is_some_and(|code| success.contains(&code)) {
+        return Ok(());
+    }
+
+    Err(failed(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim())))
+}
+
+/// Whether `boot` looks like the boot sector of a FAT filesystem.
+fn is_fat(boot: &[u8]) -> bool {
+    let bytes_per_sector = le(&boot[11..13]);
+    let per_cluster = boot[13];
+
+    boot[510..] == [0x55, 0xaa]
+        && [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+        && per_cluster.is_power_of_two()
+        && le(&boot[14..16]) > 0
+        && boot[16] > 0
+        && (le(&boot[22..24]) > 0 || le(&boot[36..40]) > 0)
+}
+
+fn grow_fat<D: Read + Write + Seek>(device: &mut D, partition: &Partition, boot: &[u8]) -> Result<bool> {
+    let bytes_per_sector = le(&boot[11..13]);
+    let per_cluster = boot[13] as u64;
+    let reserved = le(&boot[14..16]);
+    let fats = boot[16] as u64;
+    let root_entries = le(&boot[17..19]);
+    let fat_size = match le(&boot[22..24]) {
+        0 => le(&boot[36..40]),
+        size => size,
+    };
+    let total = match le(&boot[19..21]) {
+        0 => le(&boot[32..36]),
+        total => total,
+    };
+
+    let data = reserved + fats * fat_size + (root_entries * 32).div_ceil(bytes_per_sector);
+
+    if total <= data {
+        return Err(failed("FAT boot sector is inconsistent".to_owned()));
+    }
+
+    let clusters = (total - data) / per_cluster;
+
+    // The FAT type follows from the cluster count, which must stay in its range
+    let (bits, limit) = if clusters <= FAT12_CLUSTERS {
+        (12, FAT12_CLUSTERS)
+    } else if clusters <= FAT16_CLUSTERS {
+        (16, FAT16_CLUSTERS)
+    } else {
+        (32, FAT32_CLUSTERS)
+    };
+
+    // The first two entries are reserved
+    let capacity = (fat_size * bytes_per_sector * 8 / bits).saturating_sub(2);
+    let available = (partition.size / bytes_per_sector).saturating_sub(data) / per_cluster;
+    let grown = available.min(capacity).min(limit);
+
+    if grown <= clusters {
+        return Ok(false);
+    }
+
+    // Entries past the old end aren't looked at, they may not be free. An odd
+    // FAT12 entry starts in the high nibble of the last byte of the one before.
+    let first = (clusters + 2) * bits / 8;
+    let last = ((grown + 2) * bits).div_ceil(8);
+    let shared = !((clusters + 2) * bits).is_multiple_of(8);
+
+    for i in 0..fats {
+        let offset = partition.start + (reserved + i * fat_size) * bytes_per_sector + first;
+        let mut entries = vec![0u8; (last - first) as usize];
+
+        if shared {
+            read_at(device, offset, &mut entries[..1])?;
+            entries[0] &= 0x0f;
+        }
+
+        write_at(device, offset, &entries)?;
+    }
+
+    let total = data + grown * per_cluster;
+    let mut boot = boot.to_vec();
+
+    if bits < 32 && total <= 0xffff && le(&boot[19..21]) != 0 {
+        put_le(&mut boot[19..21], total);
+    } else {
+        put_le(&mut boot[19..21], 0);
+        put_le(&mut boot[32..36], total);
+    }
+
+    write_at(device, partition.start, &boot)?;
+
+    if bits == 32 {
+        let info = le(&boot[48..50]);
+        let backup = le(&boot[50..52]);
+        let valid = |sector: u64| sector != 0 && sector != 0xffff;
+
+        // The free cluster count is left for the system to work out again
+        let mut sectors = vec![];
+
+        if valid(info) {
+            sectors.push(info);
+        }
+
+        if valid(backup) {
+            write_at(device, partition.start + backup * bytes_per_sector, &boot)?;
+
+            if valid(info) {
+                sectors.push(backup + info);
+            }
+        }
+
+        for sector in sectors {
+            write_at(device, partition.start + sector * bytes_per_sector + 488, &[0xff; 4])?;
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use partitions::PartitionType;
+
+    const SECTOR: u64 = 512;
+    /// Offset of the partition into the disk
+    const START: u64 = 8 * SECTOR;
+
+    /// Boot sector of a FAT of one sector clusters and two tables, FAT32 when
+    /// it has no root directory entries.
+    fn boot_sector(reserved: u64, root_entries: u64, fat_size: u64, total: u64) -> Vec<u8> {
+        let mut boot = vec![0u8; 512];
+
+        put_le(&mut boot[11..13], SECTOR);
+        boot[13] = 1;
+        put_le(&mut boot[14..16], reserved);
+        boot[16] = 2;
+        put_le(&mut boot[17..19], root_entries);
+
+        if root_entries == 0 {
+            put_le(&mut boot[32..36], total);
+            put_le(&mut boot[36..40], fat_size);
+            put_le(&mut boot[48..50], 1);
+            put_le(&mut boot[50..52], 6);
+        } else {
+            put_le(&mut boot[19..21], total);
+            put_le(&mut boot[22..24], fat_size);
+        }
+
+        boot[510..].copy_from_slice(&[0x55, 0xaa]);
+        boot
+    }
+
+    /// Disk of the filesystem of `boot`, its tables and what follows them full of ones
+    fn disk(boot: &[u8], data: u64) -> Cursor<Vec<u8>> {
+        let mut disk = vec![0xff; (START + data * SECTOR) as usize];
+
+        disk[START as usize..START as usize + 512].copy_from_slice(boot);
+        Cursor::new(disk)
+    }
+
+    fn partition(sectors: u64) -> Partition {
+        Partition {
+            number: 1,
+            start: START,
+            size: sectors * SECTOR,
+            kind: PartitionType::Mbr(0x0c),
+            guid: None,
+            name: None,
+            flags: 0,
+        }
+    }
+
+    /// Entry `index` of the first FAT12 table, one sector after the boot sector.
+    fn fat12_entry(disk: &[u8], index: usize) -> u64 {
+        let offset = START as usize + 512 + index * 3 / 2;
+        let pair = le(&disk[offset..offset + 2]);
+
+        if index.is_multiple_of(2) { pair & 0xfff } else { pair >> 4 }
+    }
+
+    #[test]
+    fn fat_boot_sectors_are_recognized() {
+        let boot = boot_sector(1, 16, 1, 15);
+        assert!(is_fat(&boot));
+        assert!(is_fat(&boot_sector(32, 0, 600, 71232)));
+
+        let mut unsigned = boot.clone();
+        unsigned[511] = 0;
+        assert!(!is_fat(&unsigned));
+
+        let mut odd_sectors = boot.clone();
+        put_le(&mut odd_sectors[11..13], 500);
+        assert!(!is_fat(&odd_sectors));
+
+        let mut odd_clusters = boot.clone();
+        odd_clusters[13] = 3;
+        assert!(!is_fat(&odd_clusters));
+
+        let mut no_tables = boot.clone();
+        no_tables[16] = 0;
+        assert!(!is_fat(&no_tables));
+
+        let mut sizeless = boot;
+        put_le(&mut sizeless[22..24], 0);
+        assert!(!is_fat(&sizeless));
+    }
+
+    #[test]
+    fn fat12_keeps_the_entry_sharing_its_first_byte() {
+        // 1 reserved sector, 2 tables of 1 sector, 1 of root directory, 11 clusters
+        let boot = boot_sector(1, 16, 1, 15);
+        let mut disk = disk(&boot, 4);
+
+        assert!(grow_fat(&mut disk, &partition(200), &boot).unwrap());
+
+        let disk = disk.into_inner();
+        let grown = boot_sector(1, 16, 1, 200);
+
+        assert_eq!(&disk[START as usize..START as usize + 512], &grown[..]);
+        for table in 0..2 {
+            let fat = &disk[(START + (1 + table) * SECTOR) as usize..];
+
+            // Entry 12 ends in the low nibble of byte 19, entry 13 starts in its high one
+            assert_eq!(&fat[..19], &[0xff; 19]);
+            assert_eq!(fat[19], 0x0f);
+            assert!(fat[20..295].iter().all(|byte| *byte == 0));
+        }
+        assert_eq!(fat12_entry(&disk, 12), 0xfff);
+        assert!((13..198).all(|index| fat12_entry(&disk, index) == 0));
+    }
+
+    #[test]
+    fn fat16_clears_the_new_entries() {
+        // 1 reserved sector, 2 tables of 20 sectors, 1 of root directory, 5000 clusters
+        let boot = boot_sector(1, 16, 20, 5042);
+        let mut disk = disk(&boot, 42);
+
+        assert!(grow_fat(&mut disk, &partition(6000), &boot).unwrap());
+
+        let disk = disk.into_inner();
+
+        // Grown as far as the tables reach, 5120 entries, two of them reserved
+        assert_eq!(&disk[START as usize..START as usize + 512], &boot_sector(1, 16, 20, 42 + 5118)[..]);
+        for table in 0..2 {
+            let fat = &disk[(START + (1 + table * 20) * SECTOR) as usize..(START + (21 + table * 20) * SECTOR) as usize];
+
+            assert!(fat[..5002 * 2].iter().all(|byte| *byte == 0xff));
+            assert!(fat[5002 * 2..].iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn fat32_clears_the_new_entries_and_the_free_count() {
+        // 32 reserved sectors, 2 tables of 600 sectors, 70000 clusters
+        let boot = boot_sector(32, 0, 600, 1232 + 70000);
+        let mut disk = disk(&boot, 1232);
+
+        assert!(grow_fat(&mut disk, &partition(1232 + 80000), &boot).unwrap());
+
+        let disk = disk.into_inner();
+        let grown = boot_sector(32, 0, 600, 1232 + 600 * 128 - 2);
+        let sector = |n: u64| &disk[(START + n * SECTOR) as usize..(START + (n + 1) * SECTOR) as usize];
+
+        assert_eq!(sector(0), &grown[..]);
+        assert_eq!(sector(6), &grown[..]);
+        assert_eq!(&sector(1)[488..492], &[0xff; 4]);
+        assert_eq!(&sector(7)[488..492], &[0xff; 4]);
+        for table in 0..2 {
+            let fat = &disk[(START + (32 + table * 600) * SECTOR) as usize..(START + (632 + table * 600) * SECTOR) as usize];
+
+            assert!(fat[..70002 * 4].iter().all(|byte| *byte == 0xff));
+            assert!(fat[70002 * 4..].iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn full_fats_are_left_alone() {
+        let boot = boot_sector(1, 16, 1, 4 + 339);
+        let mut disk = disk(&boot, 4);
+
+        assert!(!grow_fat(&mut disk, &partition(1000), &boot).unwrap());
+        assert_eq!(&disk.get_ref()[START as usize..START as usize + 512], &boot[..]);
+    }
+}
@@ -17,167 +17,1341 @@
 
 #[macro_use] extern crate lazy_static;
 extern crate regex;
+extern crate sha1;
 extern crate sha2;
+extern crate libc;
+extern crate bzip2;
+extern crate flate2;
+extern crate xz2;
+extern crate zstd;
+extern crate zip;
+extern crate crc32fast;
+extern crate base64;
+extern crate blake2;
+extern crate blake3;
+extern crate md5;
+extern crate ed25519_dalek;
+extern crate pgp;
 
-use std::path::Path;
-use std::io::{Read,Write};
-use std::sync::mpsc::Sender;
-use std::fs::{read_dir,metadata,File,OpenOptions};
+mod error;
+mod device;
+mod blockdev;
+#[cfg(target_os = "linux")]
+mod preflight;
+mod verify;
+mod source;
+mod archive;
+mod bmap;
+mod simg;
+mod pipeline;
+mod cancel;
+mod backup;
+mod checksum;
+mod meter;
+mod sink;
+mod signature;
+mod partitions;
+mod filesystem;
+#[cfg(target_os = "linux")]
+mod monitor;
+
+use std::fmt;
+use std::io::{self,ErrorKind};
+use std::path::PathBuf;
+use std::sync::mpsc::{self,Receiver};
+use std::thread::{self,JoinHandle};
+use std::fs::{self,File,OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Duration;
 
+use sha1::Sha1;
 use sha2::{Sha256,Digest};
+use crc32fast::Hasher as Crc32;
 
-use regex::Regex;
+use checksum::{Hashers,ZEROS};
+use meter::Meter;
+use pipeline::Writer;
+
+pub use error::{Error,Result};
+pub use device::{Bus,BlockInfo,Device,DeviceFilter,Sysfs,Target,device_path};
+#[cfg(target_os = "linux")]
+pub use device::get_device_list;
+pub use blockdev::get_device_size;
+pub use source::{Compression,ImageSource};
+pub use bmap::{Bmap,ChecksumType,Range};
+pub use simg::{Chunk,SparseReader};
+pub use cancel::CancelToken;
+pub use meter::Throughput;
+pub use sink::{LogSink,NullSink,ProgressSink};
+pub use checksum::{Checksum,Digests,HashAlgorithm,hex};
+pub use signature::{SignatureCheck,SignatureKind};
+pub use backup::{BackupConfig,BackupSetting,backup_device,backup_device_cancellable};
+pub use partitions::{GptHeader,Guid,Partition,PartitionTable,PartitionType};
+#[cfg(target_os = "linux")]
+pub use preflight::{Preflight,Usage};
+#[cfg(target_os = "linux")]
+pub use monitor::{DeviceEvent,DeviceMonitor};
 
 const BUFFER4MB: usize = 4 * 1024 * 1024; // 4 MiB
+/// Expanded partitions end on a 1 MiB boundary, like partitioning tools align them
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+/// Progress is displayed in MiB
+const MIB: f64 = 1024.0 * 1024.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BurnSetting {
+    /// Read the device back after writing and compare it to the image
+    Verify,
+    /// Unmount the device's filesystems instead of refusing to write
+    Force,
+    /// Write the whole image even if a block map is found next to it
+    IgnoreBmap,
+    /// Accept a regular file as device, creating it if needed
+    AllowFileTarget,
+    /// Move the backup GPT of the image to the end of a larger device
+    RelocateGptBackup,
+    /// Grow the last partition to the end of the device
+    ExpandLastPartition,
+    /// Grow the ext2/3/4 or FAT filesystem of the expanded partition too
+    GrowFilesystem,
+    /// Drop the buffer cache of the device once written
+    FlushBuffers,
+    /// Power off the USB device once written, so that it can be pulled out
+    Eject,
+}
 
-/// Device representation
-#[derive(Clone, Debug)]
-pub struct Device {
-    /// Convenient name
-    pub name: String,
-    /// Canonical path
-    pub path: String,
-    /// File size
-    pub mbytes: u64,
+#[derive(Clone)]
+pub struct BurnConfig {
+    /// Destination device
+    pub device: String,
+    /// Source image, possibly compressed or inside a ZIP archive
+    pub image: String,
+    /// Image to pick inside the archive, when it holds several
+    pub entry: Option<String>,
+    /// Block map of the image, looked up next to the image when not given.
+    /// Refused when made for an image of another size.
+    pub bmap: Option<String>,
+    /// Flush the device every that many bytes, only once done when `None`
+    pub sync_interval: Option<u64>,
+    /// Report progress at most once every that long, after every buffer
+    /// when `None`
+    pub progress_interval: Option<Duration>,
+    /// Expected checksum of the image file: a digest in hexadecimal, with
+    /// its algorithm as in "md5:…" unless SHA-256 or SHA-512, or the path of
+    /// a checksum file listing the image, such as SHA256SUMS. Checked along
+    /// with the signature if any, before writing, or else once written.
+    pub checksum: Option<String>,
+    /// Digests of the written data to compute, all in a single pass, and
+    /// hand back in `Progress::End`. SHA-256 when verifying and left empty.
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    /// Signature the image must carry, checked before anything is written
+    pub signature: Option<SignatureCheck>,
+    /// Settings
+    pub settings: Vec<BurnSetting>,
 }
 
-/// Retrieves the canonical path of the specified device's name or path.
-pub fn device_path(devices: &Vec<Device>, input: &String) -> Option<String> {
-    if input == "/tmp/plop.img" {
-        return Some(input.clone());
+/// Step taken on a device once written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostWriteAction {
+    /// The buffer cache of the device was dropped (BLKFLSBUF)
+    FlushBuffers,
+    /// The kernel read the new partition table (BLKRRPART)
+    RereadPartitions,
+    /// The USB device was powered off
+    Eject,
+}
+
+impl fmt::Display for PostWriteAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostWriteAction::FlushBuffers => write!(f, "flushing the buffers"),
+            PostWriteAction::RereadPartitions => write!(f, "reading the partitions again"),
+            PostWriteAction::Eject => write!(f, "ejecting"),
+        }
     }
+}
+
+/// Stage of a burn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Authenticating and opening the image, checking the device
+    Preparing,
+    /// Reading through a compressed image, up to the next data to write
+    Decompressing,
+    /// Writing the image
+    Writing,
+    /// Waiting for the written data to reach the device
+    Syncing,
+    /// Reading the device back
+    Verifying,
+    /// Adjusting the partitions and filesystems, and letting the system
+    /// catch up with the device
+    Finalizing,
+}
 
-    for device in devices.iter() {
-        if *input == device.name {
-            return Some(device.path.clone());
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Preparing => write!(f, "preparing"),
+            Phase::Decompressing => write!(f, "decompressing"),
+            Phase::Writing => write!(f, "writing"),
+            Phase::Syncing => write!(f, "syncing"),
+            Phase::Verifying => write!(f, "verifying"),
+            Phase::Finalizing => write!(f, "finalizing"),
         }
     }
+}
+
+/// Progress events
+pub enum Progress {
+    /// The burn entered another phase
+    Phase {
+        phase: Phase,
+    },
+    Start {
+        total: u64,
+    },
+    Progress {
+        count: u64,
+        total: u64,
+        throughput: Throughput,
+    },
+    /// The device is being read back
+    Verifying {
+        count: u64,
+        total: u64,
+        throughput: Throughput,
+    },
+    /// The data read back differs from the image
+    VerifyFailed {
+        /// Offset of the first differing block
+        offset: u64,
+    },
+    /// A step taken after writing, which doesn't fail the burn
+    PostWrite {
+        action: PostWriteAction,
+        /// Why the step failed, if it did
+        error: Option<Error>,
+    },
+    End {
+        /// Digests of the written data, by algorithm
+        digests: Option<Digests>,
+    },
+    Error {
+        /// What went wrong
+        cause: Error,
+        /// Number of bytes successfully written before the failure
+        offset: u64,
+    },
+    /// The burn was stopped on request
+    Cancelled {
+        /// Number of bytes written and flushed before stopping
+        written: u64,
+    },
+}
 
-    let path = Path::new(input).canonicalize().unwrap().to_string_lossy().into_owned();
+/// Amounts are in MiB, rates in MiB/s.
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Progress::Phase { phase } => write!(f, "{}", phase),
+            Progress::Start { total } => write!(f, "{:.1} MiB to go", total as f64 / MIB),
+            Progress::Progress { count, total, ref throughput }
+            | Progress::Verifying { count, total, ref throughput } => {
+                write!(f, "{:.1}/{:.1} MiB, {:.1} MiB/s", count as f64 / MIB, total as f64 / MIB,
+                       throughput.current / MIB)?;
 
-    for device in devices.iter() {
-        if path == device.path {
-            return Some(path.clone())
+                match throughput.eta {
+                    Some(eta) => write!(f, ", {} s left", eta.as_secs()),
+                    None => Ok(()),
+                }
+            }
+            Progress::VerifyFailed { offset } => write!(f, "data read back differs at offset {}", offset),
+            Progress::PostWrite { action, error: None } => write!(f, "{}: done", action),
+            Progress::PostWrite { action, error: Some(ref error) } => write!(f, "{}: {}", action, error),
+            Progress::End { digests: Some(ref digests) } if !digests.is_empty() => {
+                write!(f, "done, {}", digests.to_string().replace('\n', ", "))
+            }
+            Progress::End { .. } => write!(f, "done"),
+            Progress::Error { ref cause, offset } => write!(f, "failed at offset {}: {}", offset, cause),
+            Progress::Cancelled { written } => write!(f, "cancelled, {} bytes written", written),
         }
     }
+}
+
+/// Reports `error` through the progress channel and hands it back to be returned.
+fn report(tx: &dyn ProgressSink, error: Error, offset: u64) -> Error {
+    // The caller may already be gone, the error is returned anyway.
+    let _ = tx.send(Progress::Error {
+        cause: error.clone(),
+        offset,
+    });
 
-    None
+    error
 }
 
-/// Get the list of available devices.
-#[cfg(target_os = "linux")]
-pub fn get_device_list() -> Vec<Device> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            "^(?:mmc|usb)-([^_]*)_[^-]*[^p][^a][^r][^t].?$"
-        ).unwrap();
+/// Reports that the burn entered `phase`.
+fn enter(tx: &dyn ProgressSink, phase: Phase) {
+    // Losing the receiver is noticed with the next progress event
+    let _ = tx.send(Progress::Phase { phase });
+}
+
+/// Reports the cancellation of the burn, after `written` bytes.
+fn cancelled(tx: &dyn ProgressSink, written: u64) -> Error {
+    // Nobody may be listening anymore, which is why the burn stopped.
+    let _ = tx.send(Progress::Cancelled { written });
+
+    Error::Cancelled
+}
+
+/// Reports a failed read-back verification, after `written` bytes.
+fn verify_failed(tx: &dyn ProgressSink, error: Error, written: u64) -> Error {
+    match error {
+        // Already reported as `Progress::VerifyFailed`
+        Error::VerifyMismatch { .. } => error,
+        Error::Cancelled => cancelled(tx, written),
+        error => report(tx, error, written),
     }
+}
+
+/// Writes the whole image to the device.
+///
+/// Returns the number of bytes written, and their digests when verifying or
+/// when `algorithms` are asked for.
+fn write_stream(image: &mut ImageSource, writer: &mut Writer, algorithms: &[HashAlgorithm], verify: bool,
+                interval: Option<Duration>, tx: &dyn ProgressSink, cancel: &CancelToken)
+    -> Result<(u64, Option<verify::Written>)>
+{
+    let total = image.total();
+    let hashing = verify || !algorithms.is_empty();
+    let mut tracker = verify::Tracker::new(algorithms, verify);
+
+    let mut count = 0;
+
+    tx.send(Progress::Start{total}).map_err(|_| Error::Cancelled)?;
+
+    let mut meter = Meter::new(interval);
+
+    loop {
+        cancel.check()?;
+
+        let mut buffer = writer.buffer().map_err(|e| report(tx, e, writer.written()))?;
+
+        match source::read_full(image, &mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                if hashing {
+                    tracker.input(&buffer[..n]);
+                }
+
+                writer.write(count, buffer, n)
+                    .map_err(|e| report(tx, e, writer.written()))?;
+
+                count += n as u64;
+
+                let position = image.position(count);
+
+                if let Some(throughput) = meter.update(position, total) {
+                    tx.send(Progress::Progress {
+                        count: position,
+                        total,
+                        throughput,
+                    }).map_err(|_| Error::Cancelled)?;
+                }
+            },
+            Err(e) => {
+                return Err(report(tx, Error::Read { offset: count, cause: e }, writer.written()));
+            }
+        }
+    }
+
+    Ok((count, if hashing { Some(tracker.finish()) } else { None }))
+}
+
+/// Writes only the ranges of the image listed in its block map.
+///
+/// Every range is checked against its checksum as it is written. Progress
+/// is counted in mapped bytes. Returns the written extents.
+///
+/// The whole image goes through `hashers`, the unmapped blocks as zeros.
+///
+/// Reaching a range of a compressed image may take a while, as what comes
+/// before it is decompressed: that is reported as `Phase::Decompressing`.
+fn write_mapped(image: &mut ImageSource, writer: &mut Writer, bmap: &Bmap, hashers: &mut Hashers,
+                interval: Option<Duration>, tx: &dyn ProgressSink, cancel: &CancelToken)
+    -> Result<Vec<verify::Extent>>
+{
+    let total = bmap.mapped_size();
+    let mut extents = Vec::with_capacity(bmap.ranges.len());
+
+    let mut offset = 0;
+    let mut count = 0;
+
+    tx.send(Progress::Start{total}).map_err(|_| Error::Cancelled)?;
+
+    let mut meter = Meter::new(interval);
+
+    for range in &bmap.ranges {
+        let (start, end) = bmap.span(range);
+        let mut hasher = Sha256::default();
+        // Old maps have SHA-1 checksums, the extents are checked with SHA-256 anyway
+        let mut sha1 = match bmap.checksum_type {
+            ChecksumType::Sha1 => Some(Sha1::default()),
+            ChecksumType::Sha256 => None,
+        };
+
+        // Short gaps aren't worth telling
+        let decompressing = image.compression() != Compression::None && start - offset >= BUFFER4MB as u64;
+
+        if decompressing {
+            enter(tx, Phase::Decompressing);
+        }
+
+        image.skip(start - offset)
+            .map_err(|e| report(tx, Error::Read { offset, cause: e }, writer.written()))?;
+        hashers.input_zeros(start - offset);
+
+        if decompressing {
+            enter(tx, Phase::Writing);
+        }
+
+        offset = start;
+
+        while offset < end {
+            cancel.check()?;
 
-    let mut paths = Vec::new();
+            let size = (end - offset).min(BUFFER4MB as u64) as usize;
+            let mut buffer = writer.buffer().map_err(|e| report(tx, e, writer.written()))?;
+            let n = source::read_full(image, &mut buffer[..size])
+                .map_err(|e| report(tx, Error::Read { offset, cause: e }, writer.written()))?;
 
-    for path in read_dir("/dev/disk/by-id/").unwrap() {
-        let path = path.unwrap().path();
-        let name = path.file_name().unwrap().to_string_lossy().into_owned();
-        let path = path.canonicalize().unwrap().to_string_lossy().into_owned();
-        let size = metadata(path.clone()).unwrap().len() / (1024*1024);
+            if n < size {
+                let cause = io::Error::new(ErrorKind::UnexpectedEof, "image shorter than its block map");
+                return Err(report(tx, Error::Read { offset: offset + n as u64, cause }, writer.written()));
+            }
+
+            hasher.update(&buffer[..n]);
+            hashers.input(&buffer[..n]);
+
+            if let Some(ref mut sha1) = sha1 {
+                sha1.update(&buffer[..n]);
+            }
+
+            writer.write(offset, buffer, n)
+                .map_err(|e| report(tx, e, writer.written()))?;
+
+            offset += n as u64;
+            count += n as u64;
 
-        if let Some(caps) = RE.captures(&name) {
-            paths.push(Device{
-                name: (&caps[1]).to_owned(),
-                path: path,
-                mbytes: size,
-            });
+            if let Some(throughput) = meter.update(count, total) {
+                tx.send(Progress::Progress { count, total, throughput }).map_err(|_| Error::Cancelled)?;
+            }
+        }
+
+        let digest = hasher.finalize().to_vec();
+        let range_digest = match sha1 {
+            Some(sha1) => sha1.finalize().to_vec(),
+            None => digest.clone(),
+        };
+
+        if range.checksum.as_ref().is_some_and(|checksum| *checksum != range_digest) {
+            return Err(report(tx, Error::BmapChecksum { offset: start }, writer.written()));
         }
+
+        extents.push(verify::Extent { start, end, digest });
+    }
+
+    // The size of some compressed images is only known once read to their end
+    if image.size().is_none() {
+        let tail = io::copy(image, &mut io::sink())
+            .map_err(|e| report(tx, Error::Read { offset, cause: e }, writer.written()))?;
+
+        bmap.check_image_size(offset + tail).map_err(|e| report(tx, e, writer.written()))?;
     }
 
-    paths
+    hashers.input_zeros(bmap.image_size.saturating_sub(offset));
+
+    Ok(extents)
 }
 
+/// Image files must end up as large as the image, even if its tail was skipped.
+fn extend_file(device: &File, size: u64) -> io::Result<()> {
+    if !blockdev::is_block_device(device) && device.metadata()?.len() < size {
+        device.set_len(size)?;
+    }
 
-/// Get the device size in bytes.
-pub fn get_device_size() -> Result<u64,String> {
-    Ok(0)
+    Ok(())
 }
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum BurnSetting {
-    Verify,
+/// Moves the backup GPT to the end of the device, see `partitions::relocate_gpt_backup`.
+fn relocate_gpt_backup(path: &str) -> Result<()> {
+    let mut device = OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| Error::device_open(path, e))?;
+    let size = blockdev::file_size(&device)?;
+
+    if partitions::relocate_gpt_backup(&mut device, size)? {
+        device.sync_data()?;
+    }
+
+    Ok(())
 }
 
-pub struct BurnConfig {
-    /// Destination device
-    pub device: String,
-    /// Source image
-    pub image: String,
-    /// Settings
-    pub settings: Vec<BurnSetting>,
+/// Grows the last partition to the end of the device, and its filesystem if `grow`.
+fn expand_last_partition(path: &str, grow: bool) -> Result<()> {
+    let mut device = OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| Error::device_open(path, e))?;
+    let size = blockdev::file_size(&device)?;
+
+    let sector_size = blockdev::logical_sector_size(&device)?;
+    let partition = partitions::expand_last_partition(&mut device, size, sector_size, PARTITION_ALIGNMENT)?;
+
+    device.sync_data()?;
+    drop(device);
+
+    if let (Some(partition), true) = (partition, grow) {
+        filesystem::grow_filesystem(path, &partition)?;
+    }
+
+    Ok(())
 }
 
-/// Progress events
-pub enum Progress {
-    Start {
-        total: u64,
-    },
-    Progress {
-        count: u64,
-        total: u64,
-    },
-    End {
-        digest: Option<Vec<u8>>,
-    },
-    Error,
+/// Lets the system catch up with the new content of the device.
+///
+/// The partition table is always read again, so that the new partitions
+/// show up without replugging the device. Failures are only reported.
+#[cfg(target_os = "linux")]
+fn post_write(config: &BurnConfig, tx: &dyn ProgressSink) {
+    let mut actions = vec![];
+
+    if config.settings.contains(&BurnSetting::FlushBuffers) {
+        actions.push(PostWriteAction::FlushBuffers);
+    }
+
+    actions.push(PostWriteAction::RereadPartitions);
+
+    if config.settings.contains(&BurnSetting::Eject) {
+        actions.push(PostWriteAction::Eject);
+    }
+
+    for action in actions {
+        let error = post_write_action(action, &config.device).err();
+        let _ = tx.send(Progress::PostWrite { action, error });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn post_write_action(action: PostWriteAction, path: &str) -> Result<()> {
+    let open = || File::open(path).map_err(|e| Error::device_open(path, e));
+
+    match action {
+        PostWriteAction::FlushBuffers => blockdev::flush_buffers(&open()?)?,
+        PostWriteAction::RereadPartitions => blockdev::reread_partitions(&open()?)?,
+        PostWriteAction::Eject => {
+            // Through the symbolic links in /dev/disk, down to the kernel name
+            let name = fs::canonicalize(path)?.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| Error::DeviceNotFound(path.to_owned()))?;
+
+            Sysfs::default().eject(&name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Expands an Android sparse image onto the device.
+///
+/// "Don't care" chunks are seeked over, fill chunks are written by
+/// repeating their pattern. The CRC32 chunks and the header checksum, if
+/// any, are checked along the way. Returns the written extents.
+///
+/// The expanded image goes through `hashers`, "don't care" chunks as zeros.
+fn write_sparse(image: &mut ImageSource, writer: &mut Writer, mut sparse: SparseReader, hashers: &mut Hashers,
+                interval: Option<Duration>, tx: &dyn ProgressSink, cancel: &CancelToken)
+    -> Result<Vec<verify::Extent>>
+{
+    let total = sparse.size();
+    let mut extents = Vec::new();
+    let mut crc = Crc32::new();
+
+    let mut offset = 0u64;
+
+    tx.send(Progress::Start{total}).map_err(|_| Error::Cancelled)?;
+
+    let mut meter = Meter::new(interval);
+
+    while let Some(chunk) = sparse.next_chunk(image).map_err(|e| report(tx, e, writer.written()))? {
+        let length = match chunk {
+            Chunk::Raw(length) | Chunk::Fill(_, length) | Chunk::DontCare(length) => length,
+            Chunk::Crc32(_) => 0,
+        };
+
+        if offset.checked_add(length).is_none_or(|end| end > total) {
+            return Err(report(tx, Error::InvalidSparse("chunks beyond the image size".to_owned()), writer.written()));
+        }
+
+        match chunk {
+            Chunk::Raw(length) | Chunk::Fill(_, length) => {
+                let start = offset;
+                let end = offset + length;
+                let mut hasher = Sha256::default();
+
+                while offset < end {
+                    cancel.check()?;
+
+                    let size = (end - offset).min(BUFFER4MB as u64) as usize;
+                    let mut buffer = writer.buffer().map_err(|e| report(tx, e, writer.written()))?;
+
+                    match chunk {
+                        Chunk::Fill(value, _) => {
+                            for word in buffer[..size].chunks_mut(4) {
+                                word.copy_from_slice(&value.to_le_bytes());
+                            }
+                        }
+                        _ => {
+                            let n = source::read_full(image, &mut buffer[..size])
+                                .map_err(|e| report(tx, Error::Read { offset, cause: e }, writer.written()))?;
+
+                            if n < size {
+                                return Err(report(tx, Error::InvalidSparse("truncated image".to_owned()), writer.written()));
+                            }
+                        }
+                    }
+
+                    hasher.update(&buffer[..size]);
+                    hashers.input(&buffer[..size]);
+                    crc.update(&buffer[..size]);
+
+                    writer.write(offset, buffer, size)
+                        .map_err(|e| report(tx, e, writer.written()))?;
+
+                    offset += size as u64;
+
+                    if let Some(throughput) = meter.update(offset, total) {
+                        tx.send(Progress::Progress { count: offset, total, throughput }).map_err(|_| Error::Cancelled)?;
+                    }
+                }
+
+                extents.push(verify::Extent { start, end, digest: hasher.finalize().to_vec() });
+            }
+            Chunk::DontCare(length) => {
+                // Checksums count the skipped blocks as zeros
+                let mut remaining = length;
+
+                while remaining > 0 {
+                    let size = remaining.min(ZEROS.len() as u64);
+                    crc.update(&ZEROS[..size as usize]);
+                    remaining -= size;
+                }
+
+                hashers.input_zeros(length);
+                offset += length;
+
+                if let Some(throughput) = meter.update(offset, total) {
+                    tx.send(Progress::Progress { count: offset, total, throughput }).map_err(|_| Error::Cancelled)?;
+                }
+            }
+            Chunk::Crc32(expected) => {
+                if crc.clone().finalize() != expected {
+                    return Err(report(tx, Error::SparseChecksum { offset }, writer.written()));
+                }
+            }
+        }
+    }
+
+    if offset != total {
+        return Err(report(tx, Error::InvalidSparse("chunks don't cover the image".to_owned()), writer.written()));
+    }
+
+    if sparse.checksum != 0 && crc.finalize() != sparse.checksum {
+        return Err(report(tx, Error::SparseChecksum { offset }, writer.written()));
+    }
+
+    Ok(extents)
 }
 
 /// Writes the desired image to the specified device.
-pub fn burn_image(config: BurnConfig, tx: Sender<Progress>) {
-    let total = metadata(config.image.clone()).unwrap().len();
-    let mut image = File::open(config.image).expect("Can't open image");
-    let mut device = OpenOptions::new().write(true).open(config.device).expect("Can't open device");
+///
+/// Compressed images are decompressed on the fly. Progress is counted in
+/// uncompressed bytes when the container records the uncompressed size,
+/// and in compressed bytes otherwise.
+///
+/// Android sparse images are expanded on the fly. Otherwise, when a block
+/// map is given or found next to the image, only the mapped blocks are
+/// written. In both cases only the written parts are verified, and the
+/// digests are the ones of the expanded image, skipped blocks read as zeros.
+///
+/// The image is read while the previous buffers are being written, and the
+/// device is only flushed at the end, or every `sync_interval` bytes.
+///
+/// Every `Phase` the burn goes through is announced by a `Progress::Phase`
+/// event. Progress events carry the throughput, and are sent at most every
+/// `progress_interval`. Events go to `tx`: a channel, a closure, or any
+/// other `ProgressSink`.
+///
+/// Failures are both reported as a `Progress::Error` event and returned.
+pub fn burn_image<S: ProgressSink>(config: BurnConfig, tx: S) -> Result<()> {
+    burn_image_cancellable(config, tx, CancelToken::new())
+}
+
+/// Writes the desired image to the specified device, until `cancel` is triggered.
+///
+/// Cancellation, or the sink failing, as when the receiver of a channel is
+/// dropped, stops the burn at the next chunk boundary. The data already queued is written and flushed,
+/// then `Progress::Cancelled` is sent and `Error::Cancelled` returned.
+///
+/// See `burn_image` for the rest.
+pub fn burn_image_cancellable<S: ProgressSink>(config: BurnConfig, tx: S, cancel: CancelToken) -> Result<()> {
+    enter(&tx, Phase::Preparing);
+
+    let (image, checksum) = open_image(&config).map_err(|e| report(&tx, e, 0))?;
+
+    burn_source(image, checksum, config, &tx, cancel)
+}
+
+/// Opens the image of `config`, once authenticated, hashing it on the way if
+/// its checksum is expected.
+///
+/// The image is burnt from the very file handle its signature was checked on.
+/// The checksum is then checked as the signature is, before writing anything,
+/// and isn't handed back.
+fn open_image(config: &BurnConfig) -> Result<(ImageSource, Option<Checksum>)> {
+    let mut file = File::open(&config.image).map_err(|e| Error::image_open(&config.image, e))?;
+
+    let mut checksum = match config.checksum {
+        Some(ref checksum) => Some(Checksum::resolve(checksum, &config.image)?),
+        None => None,
+    };
+
+    if let Some(ref signature) = config.signature {
+        match checksum.take() {
+            Some(expected) => {
+                let (_, digest) = signature.verify_hashing(&mut file, &config.image, expected.algorithm)?;
+
+                check_digest(&expected, digest, None)?;
+            }
+            None => {
+                signature.verify_file(&mut file, &config.image)?;
+            }
+        }
+    }
+
+    let mut image = ImageSource::open_file(file, &config.image, config.entry.as_deref())?;
+
+    if let Some(ref checksum) = checksum {
+        image.hash_file(checksum.algorithm)?;
+    }
+
+    Ok((image, checksum))
+}
+
+/// Compares the `digest` of the image file to the `expected` one, the image
+/// having been written to `device` if given.
+fn check_digest(expected: &Checksum, digest: Vec<u8>, device: Option<&str>) -> Result<()> {
+    if digest != expected.digest {
+        return Err(Error::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual: Checksum { algorithm: expected.algorithm, digest }.to_string(),
+            device: device.map(str::to_owned),
+        });
+    }
+
+    Ok(())
+}
+
+/// Compares the digest of the image file, once read and written to `device`,
+/// to the expected one.
+fn check_checksum(image: &ImageSource, expected: &Checksum, device: &str) -> Result<()> {
+    let digest = image.file_digest()
+        .unwrap_or_else(|| Err(io::Error::other("image file not hashed")))?;
+
+    check_digest(expected, digest, Some(device))
+}
+
+/// Writes the already opened `image` to the device of `config`.
+///
+/// The image file is checked against `checksum` once written, before
+/// verifying the device.
+fn burn_source(mut image: ImageSource, checksum: Option<Checksum>, config: BurnConfig, tx: &dyn ProgressSink,
+               cancel: CancelToken)
+    -> Result<()>
+{
+    let sparse = match image.peek(simg::MAGIC.len()) {
+        Ok(header) => simg::is_sparse(header),
+        Err(e) => return Err(report(tx, Error::Read { offset: 0, cause: e }, 0)),
+    };
+    let sparse = if sparse {
+        Some(SparseReader::new(&mut image).map_err(|e| report(tx, e, 0))?)
+    } else {
+        None
+    };
+
+    // A block map describes the expanded image, not a sparse one
+    let bmap = if sparse.is_some() || config.settings.contains(&BurnSetting::IgnoreBmap) {
+        None
+    } else {
+        match config.bmap.as_ref().map(PathBuf::from).or_else(|| Bmap::find_for(&config.image)) {
+            Some(path) => Some(Bmap::open(path).map_err(|e| report(tx, e, 0))?),
+            None => None,
+        }
+    };
+
+    // A stale map would leave data out
+    if let (Some(bmap), Some(size)) = (&bmap, image.size()) {
+        bmap.check_image_size(size).map_err(|e| report(tx, e, 0))?;
+    }
+
+    let target = Target::resolve(&config.device, config.settings.contains(&BurnSetting::AllowFileTarget))
+        .map_err(|e| report(tx, e, 0))?;
+
+    #[cfg(target_os = "linux")]
+    {
+        if !target.is_file() {
+            Preflight::default().check(&config.device, config.settings.contains(&BurnSetting::Force))
+                .map_err(|e| report(tx, e, 0))?;
+        }
+    }
+
+    // Image files are replaced, not patched, and devices opened exclusively so
+    // that nothing mounts them while they are being written
+    let mut options = OpenOptions::new();
+    options.write(true).create(target.is_file()).truncate(target.is_file());
+    if !target.is_file() {
+        options.custom_flags(libc::O_EXCL);
+    }
+    let device = options.open(&config.device)
+        .map_err(|e| report(tx, Error::device_open(&config.device, e), 0))?;
+    let capacity = blockdev::file_size(&device)
+        .map_err(|e| report(tx, Error::Io(e), 0))?;
+
+    // Regular files grow as needed, only block devices have a fixed capacity
+    let size = sparse.as_ref().map(|sparse| sparse.size())
+        .or(bmap.as_ref().map(|bmap| bmap.image_size))
+        .or(image.size());
+
+    if let Some(size) = size {
+        if blockdev::is_block_device(&device) && size > capacity {
+            return Err(report(tx, Error::ImageTooLarge { image: size, device: capacity }, 0));
+        }
+    }
 
     let verify = config.settings.contains(&BurnSetting::Verify);
-    let mut hasher = Sha256::default();
+    let algorithms = if config.hash_algorithms.is_empty() && verify {
+        vec![HashAlgorithm::Sha256]
+    } else {
+        config.hash_algorithms.clone()
+    };
 
-    let mut count = 0;
+    let interval = config.progress_interval;
+    let mut writer = Writer::new(device, BUFFER4MB, config.sync_interval);
 
-    let mut buffer = vec![0u8; BUFFER4MB];
+    enter(tx, Phase::Writing);
 
-    tx.send(Progress::Start{total}).unwrap();
+    // Only the stream is hashed by `write_stream` itself
+    let mut hashers = Hashers::new(&algorithms);
 
-    loop {
-        match image.read(&mut *buffer) {
-            Ok(0) => {
-                let digest = if verify {
-                    Some(hasher.result().as_slice().to_owned())
-                } else {
-                    None
-                };
-
-                tx.send(Progress::End {
-                    digest: digest
-                }).unwrap();
+    let result = match (sparse, bmap) {
+        (Some(sparse), _) => write_sparse(&mut image, &mut writer, sparse, &mut hashers, interval, tx, &cancel)
+            .map(|extents| (Some(extents), None)),
+        (None, Some(ref bmap)) => write_mapped(&mut image, &mut writer, bmap, &mut hashers, interval, tx, &cancel)
+            .map(|extents| (Some(extents), None)),
+        (None, None) => write_stream(&mut image, &mut writer, &algorithms, verify, interval, tx, &cancel)
+            .map(|stream| (None, Some(stream))),
+    };
 
-                break;
+    let written = writer.written();
+
+    // Once cancelled, what is already queued is still written and flushed
+    let (extents, stream) = match result {
+        Ok(result) => result,
+        Err(Error::Cancelled) => {
+            let (_, written) = writer.finish().map_err(|e| report(tx, e, written))?;
+            return Err(cancelled(tx, written));
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Archive entries are only checked once read to their end
+    if image.entry().is_some() {
+        io::copy(&mut image, &mut io::sink())
+            .map_err(|e| report(tx, Error::Read { offset: written, cause: e }, written))?;
+    }
+
+    enter(tx, Phase::Syncing);
+
+    let (device, written) = writer.finish().map_err(|e| report(tx, e, written))?;
+
+    if let Some(ref checksum) = checksum {
+        check_checksum(&image, checksum, &config.device).map_err(|e| report(tx, e, written))?;
+    }
+
+    if let (Some(_), Some(size)) = (&extents, size) {
+        extend_file(&device, size).map_err(|e| report(tx, Error::Io(e), written))?;
+    }
+
+    drop(device);
+
+    if verify {
+        enter(tx, Phase::Verifying);
+    }
+
+    let digests = match (extents, stream) {
+        (Some(extents), _) => {
+            if verify {
+                verify::read_back_extents(&config.device, &extents, interval, tx, &cancel)
+                    .map_err(|e| verify_failed(tx, e, written))?;
             }
-            Ok(n) => {
-                count += n as u64;
 
-                if verify {
-                    hasher.input(&buffer[..n]);
-                }
+            if algorithms.is_empty() { None } else { Some(hashers.result()) }
+        }
+        (None, Some((count, Some(hashed)))) => {
+            if verify {
+                verify::read_back(&config.device, count, &hashed, interval, tx, &cancel)
+                    .map_err(|e| verify_failed(tx, e, written))?;
+            }
+
+            Some(hashed.digests)
+        }
+        (None, _) => None,
+    };
 
-                device.write(&buffer[..n]).unwrap();
-                device.sync_data().unwrap();
+    enter(tx, Phase::Finalizing);
 
-                tx.send(Progress::Progress {
-                    count: count,
-                    total: total,
-                }).unwrap();
-            },
-            Err(_) => {
-                tx.send(Progress::Error).unwrap();
+    // After verifying, as it changes what was written
+    if config.settings.contains(&BurnSetting::RelocateGptBackup) {
+        relocate_gpt_backup(&config.device).map_err(|e| report(tx, e, written))?;
+    }
+
+    if config.settings.contains(&BurnSetting::ExpandLastPartition) {
+        expand_last_partition(&config.device, config.settings.contains(&BurnSetting::GrowFilesystem))
+            .map_err(|e| report(tx, e, written))?;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if !target.is_file() {
+            post_write(&config, tx);
+        }
+    }
+
+    // The burn is complete, whether someone is still listening or not
+    let _ = tx.send(Progress::End { digests });
+
+    Ok(())
+}
+
+/// Sink of one of the burns of `burn_image_multi`, cancelling all of them
+/// once nobody listens anymore.
+struct Shared<S> {
+    sink: S,
+    cancel: CancelToken,
+}
+
+impl<S: ProgressSink> ProgressSink for Shared<S> {
+    fn send(&self, event: Progress) -> Result<()> {
+        self.sink.send(event).inspect_err(|_| self.cancel.cancel())
+    }
+}
+
+/// Writes the image of `config` to every one of `devices` at once.
+///
+/// The image is read and decompressed once, then fed to one burn per
+/// device, `config.device` being ignored. Events of every device go to the
+/// sink `sinks` builds from its path. A failing device doesn't stop the
+/// others, and the outcome of every burn is returned, in the order of
+/// `devices`.
+///
+/// Cancelling `cancel`, or any sink failing, stops all the burns, see
+/// `burn_image_cancellable`.
+pub fn burn_image_multi<F, S>(config: BurnConfig, devices: &[String], sinks: F, cancel: CancelToken)
+    -> Vec<Result<()>>
+    where F: Fn(&str) -> S, S: ProgressSink + Send + 'static
+{
+    let sinks: Vec<_> = devices.iter().map(|device| Shared { sink: sinks(device), cancel: cancel.clone() }).collect();
+
+    for tx in &sinks {
+        enter(tx, Phase::Preparing);
+    }
+
+    let (image, checksum) = match open_image(&config) {
+        Ok(opened) => opened,
+        Err(e) => return sinks.iter().map(|tx| Err(report(tx, e.clone(), 0))).collect(),
+    };
+
+    let burns: Vec<_> = image.tee(devices.len()).into_iter().zip(devices).zip(sinks).map(|((image, device), tx)| {
+        let config = BurnConfig { device: device.clone(), ..config.clone() };
+        let checksum = checksum.clone();
+        let cancel = cancel.clone();
+
+        thread::spawn(move || burn_source(image, checksum, config, &tx, cancel))
+    }).collect();
+
+    burns.into_iter().map(|burn| {
+        burn.join().unwrap_or_else(|_| Err(Error::Io(io::Error::other("burn thread panicked"))))
+    }).collect()
+}
+
+/// Burn running in a background thread
+pub struct BurnHandle {
+    /// Progress events of the burn
+    pub progress: Receiver<Progress>,
+    cancel: CancelToken,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl BurnHandle {
+    /// Starts burning in a background thread.
+    pub fn spawn(config: BurnConfig) -> BurnHandle {
+        let (tx, progress) = mpsc::channel();
+        let cancel = CancelToken::new();
+        let token = cancel.clone();
+
+        BurnHandle {
+            progress,
+            cancel,
+            thread: thread::spawn(move || burn_image_cancellable(config, tx, token)),
+        }
+    }
+
+    /// Asks the burn to stop, see `burn_image_cancellable`.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
 
+    /// Token cancelling the burn, to be handed to other threads.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Waits for the burn to end.
+    pub fn join(self) -> Result<()> {
+        self.thread.join().unwrap_or_else(|_| {
+            Err(Error::Io(io::Error::other("burn thread panicked")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs::{create_dir_all,remove_dir_all,write};
+    use std::path::{Path,PathBuf};
+    use std::process;
+
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use ed25519_dalek::{Signer,SigningKey};
+    use sha1::Sha1;
+
+    /// Empty directory of fixtures, unique to the test `name`
+    fn fixtures(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("acetylene-burn-{}-{}", name, process::id()));
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Burns `image` to the regular file `device`.
+    fn config(image: &Path, device: &Path) -> BurnConfig {
+        BurnConfig {
+            device: device.to_str().unwrap().to_owned(),
+            image: image.to_str().unwrap().to_owned(),
+            entry: None,
+            bmap: None,
+            sync_interval: None,
+            progress_interval: None,
+            checksum: None,
+            hash_algorithms: vec![],
+            signature: None,
+            settings: vec![BurnSetting::AllowFileTarget],
+        }
+    }
+
+    /// Signs `image` with signify, trusting the signing key.
+    fn sign(root: &Path, image: &[u8]) -> SignatureCheck {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut public = b"Ed".to_vec();
+        let mut signature = b"Ed".to_vec();
+
+        public.extend_from_slice(&[1; 8]);
+        public.extend_from_slice(key.verifying_key().as_bytes());
+        signature.extend_from_slice(&[1; 8]);
+        signature.extend_from_slice(&key.sign(image).to_bytes());
+
+        write(root.join("key.pub"), format!("untrusted comment: key\n{}\n", BASE64.encode(public))).unwrap();
+        write(root.join("image.sig"), format!("untrusted comment: sig\n{}\n", BASE64.encode(signature))).unwrap();
+
+        SignatureCheck {
+            signature: root.join("image.sig").to_str().unwrap().to_owned(),
+            keys: vec![root.join("key.pub").to_str().unwrap().to_owned()],
+            signed_file: None,
+        }
+    }
+
+    const WRONG_SHA256: &str = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn late_checksum_mismatches_name_the_written_device() {
+        let root = fixtures("checksum");
+        let image = root.join("image.img");
+        let device = root.join("device.img");
+
+        write(&image, vec![0x5a; 100_000]).unwrap();
+
+        let mut config = config(&image, &device);
+        config.checksum = Some(WRONG_SHA256.to_owned());
+
+        let result = burn_image(config, NullSink);
+
+        let _ = remove_dir_all(&root);
+        match result {
+            Err(Error::ChecksumMismatch { device: Some(written), .. }) => assert_eq!(written, device.to_str().unwrap()),
+            _ => panic!("checksum mismatch not reported as written"),
+        }
+    }
+
+    #[test]
+    fn checksums_of_signed_images_are_checked_before_writing() {
+        let root = fixtures("signed-checksum");
+        let image = root.join("image.img");
+        let device = root.join("device.img");
+        let content = vec![0xa5; 100_000];
+
+        write(&image, &content).unwrap();
+
+        let mut config = config(&image, &device);
+        config.checksum = Some(WRONG_SHA256.to_owned());
+        config.signature = Some(sign(&root, &content));
+
+        let result = burn_image(config.clone(), NullSink);
+        let written = device.exists();
+
+        config.checksum = Some(format!("sha256:{}", hex(&Sha256::digest(&content))));
+        let burnt = burn_image(config, NullSink).map(|_| fs::read(&device).unwrap());
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(result, Err(Error::ChecksumMismatch { device: None, .. })));
+        assert!(!written);
+        assert_eq!(burnt.unwrap(), content);
+    }
+
+    /// Version 1 map of `image`, 4 KiB blocks, mapping its second block
+    fn sha1_bmap(image_size: usize, block: &[u8]) -> String {
+        format!(
+            "<bmap version=\"1.2\">\n<ImageSize>{}</ImageSize>\n<BlockSize>4096</BlockSize>\n\
+             <BlockMap>\n<Range sha1=\"{}\">1</Range>\n</BlockMap>\n</bmap>\n",
+            image_size, hex(&Sha1::digest(block))
+        )
+    }
+
+    #[test]
+    fn sha1_block_maps_are_checked() {
+        let root = fixtures("bmap-sha1");
+        let image = root.join("image.img");
+        let device = root.join("device.img");
+        let mut content = vec![0u8; 3 * 4096];
+
+        content[4096..8192].copy_from_slice(&[0x42; 4096]);
+        write(&image, &content).unwrap();
+
+        write(root.join("image.img.bmap"), sha1_bmap(content.len(), &content[4096..8192])).unwrap();
+        let burnt = burn_image(config(&image, &device), NullSink).map(|_| fs::read(&device).unwrap());
+
+        write(root.join("image.img.bmap"), sha1_bmap(content.len(), &[0x43; 4096])).unwrap();
+        let result = burn_image(config(&image, &device), NullSink);
+
+        let _ = remove_dir_all(&root);
+        assert_eq!(burnt.unwrap(), content);
+        assert!(matches!(result, Err(Error::BmapChecksum { offset: 4096 })));
+    }
+
+    /// Sparse image of `blocks` 4 KiB blocks, its first `filled` ones set to `value`
+    fn sparse_fill(blocks: u32, filled: u32, value: u32) -> Vec<u8> {
+        let mut image = simg::MAGIC.to_vec();
+
+        for field in &[1u16, 0, 28, 12] {
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[4096, blocks, 1, 0, 0xcac2, filled, 16, value] {
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn sparse_chunks_beyond_the_image_are_refused() {
+        let root = fixtures("sparse-overflow");
+        let image = root.join("image.img");
+        let device = root.join("device.img");
+
+        write(&image, sparse_fill(2, 3, 0x1111_1111)).unwrap();
+
+        let result = burn_image(config(&image, &device), NullSink);
+        let written = fs::metadata(&device).map(|meta| meta.len()).unwrap_or(0);
+
+        let _ = remove_dir_all(&root);
+        match result {
+            Err(Error::InvalidSparse(reason)) => assert_eq!(reason, "chunks beyond the image size"),
+            _ => panic!("oversized chunk written"),
+        }
+        assert_eq!(written, 0);
+    }
+
+    /// SHA-256 handed back at the end of the verified burn of `image`.
+    fn verified_sha256(image: &Path, device: &Path) -> Vec<u8> {
+        let (tx, rx) = mpsc::channel();
+        let mut config = config(image, device);
+
+        config.settings.push(BurnSetting::Verify);
+        burn_image(config, tx).unwrap();
+
+        rx.try_iter().filter_map(|event| match event {
+            Progress::End { digests: Some(digests) } => digests.get(HashAlgorithm::Sha256).map(|d| d.to_vec()),
+            _ => None,
+        }).next().expect("no SHA-256 when verifying")
+    }
+
+    #[test]
+    fn verifying_hands_back_the_sha256_of_any_image() {
+        let root = fixtures("verify-digests");
+        let device = root.join("device.img");
+
+        let stream = root.join("stream.img");
+        let stream_content = vec![0x11; 4 * 4096];
+        write(&stream, &stream_content).unwrap();
+
+        let sparse = root.join("sparse.img");
+        write(&sparse, sparse_fill(4, 4, 0x1111_1111)).unwrap();
+
+        let mapped = root.join("mapped.img");
+        let mut mapped_content = vec![0u8; 3 * 4096];
+        mapped_content[4096..8192].copy_from_slice(&[0x42; 4096]);
+        write(&mapped, &mapped_content).unwrap();
+        write(root.join("mapped.img.bmap"), sha1_bmap(mapped_content.len(), &mapped_content[4096..8192])).unwrap();
+
+        let digests = [&stream, &sparse, &mapped].iter().map(|image| verified_sha256(image, &device)).collect::<Vec<_>>();
+
+        let _ = remove_dir_all(&root);
+        assert_eq!(digests[0], Sha256::digest(&stream_content).to_vec());
+        assert_eq!(digests[1], digests[0]);
+        assert_eq!(digests[2], Sha256::digest(&mapped_content).to_vec());
+    }
+
+    #[test]
+    fn stale_block_maps_are_refused() {
+        let root = fixtures("bmap-stale");
+        let image = root.join("image.img");
+        let device = root.join("device.img");
+
+        write(&image, vec![0x42; 5 * 4096]).unwrap();
+        write(root.join("image.img.bmap"), sha1_bmap(3 * 4096, &[0x42; 4096])).unwrap();
+
+        let result = burn_image(config(&image, &device), NullSink);
+
+        let _ = remove_dir_all(&root);
+        match result {
+            Err(Error::InvalidBmap(reason)) => assert_eq!(reason, "made for an image of 12288 bytes, not 20480"),
+            _ => panic!("stale block map used"),
+        }
+    }
+
+    /// Image of 64 MiB, 16 chunks to write, and the target to write it to
+    fn large_image(root: &Path) -> (PathBuf, PathBuf) {
+        let image = root.join("image.img");
+
+        write(&image, vec![0x3c; 64 * 1024 * 1024]).unwrap();
+        (image, root.join("device.img"))
+    }
+
+    /// Whether a file descriptor of this process, a writer thread's, is open on `path`
+    fn is_open(path: &Path) -> bool {
+        let path = fs::canonicalize(path).unwrap();
+
+        fs::read_dir("/proc/self/fd").unwrap().filter_map(|entry| entry.ok())
+            .any(|entry| fs::read_link(entry.path()).is_ok_and(|target| target == path))
+    }
+
+    /// Bytes written according to the `Progress::Cancelled` of `events`
+    fn cancelled_after(events: &[Progress]) -> Option<u64> {
+        events.iter().find_map(|event| match *event {
+            Progress::Cancelled { written } => Some(written),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn cancelling_stops_the_burn_mid_stream() {
+        let root = fixtures("cancel");
+        let (image, device) = large_image(&root);
+        let cancel = CancelToken::new();
+        let events = RefCell::new(vec![]);
+
+        let result = burn_image_cancellable(config(&image, &device), |event: Progress| {
+            if let Progress::Progress { .. } = event {
+                cancel.cancel();
+            }
+            events.borrow_mut().push(event);
+        }, cancel.clone());
+
+        let open = is_open(&device);
+        let length = fs::metadata(&device).unwrap().len();
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(!open);
+
+        // What was queued when cancelled is still written
+        let written = cancelled_after(&events.borrow()).expect("cancellation not reported");
+        assert_eq!(written, length);
+        assert!(written >= BUFFER4MB as u64 && written < 64 * 1024 * 1024);
+    }
+
+    /// Starts burning `image`, returning once the first chunk is written.
+    fn started(image: &Path, device: &Path) -> BurnHandle {
+        let handle = BurnHandle::spawn(config(image, device));
+
+        for event in handle.progress.iter() {
+            if let Progress::Progress { .. } = event {
                 break;
             }
         }
+        handle
+    }
+
+    #[test]
+    fn burn_handles_stop_when_cancelled() {
+        let root = fixtures("cancel-handle");
+        let (image, device) = large_image(&root);
+        let handle = started(&image, &device);
+
+        handle.cancel();
+        let events = handle.progress.iter().collect::<Vec<_>>();
+        let result = handle.join();
+        let open = is_open(&device);
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(cancelled_after(&events).is_some());
+        assert!(!open);
+    }
+
+    #[test]
+    fn burn_handles_stop_when_nobody_listens() {
+        let root = fixtures("cancel-unheard");
+        let (image, device) = large_image(&root);
+        let mut handle = started(&image, &device);
+
+        // Drops the receiver of the events
+        handle.progress = mpsc::channel().1;
+        let result = handle.join();
+        let open = is_open(&device);
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(!open);
     }
 }
@@ -0,0 +1,93 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Transfer rate measurement, and pacing of the progress events.
+
+use std::time::{Duration,Instant};
+
+/// Speed of a transfer, in the unit its progress is counted in per second
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throughput {
+    /// Rate since the previous event
+    pub current: f64,
+    /// Rate since the start of the transfer
+    pub average: f64,
+    /// Time left at the current rate, unknown until some data went through
+    pub eta: Option<Duration>,
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+
+    if seconds > 0.0 {
+        bytes as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// Measures the throughput of a transfer, and tells when its progress is
+/// due to be reported.
+pub struct Meter {
+    interval: Option<Duration>,
+    start: Instant,
+    /// Time and count of the last report
+    last: (Instant, u64),
+}
+
+impl Meter {
+    /// Starts measuring. Progress is then reported at most once every
+    /// `interval`, or on every update when `None`.
+    pub fn new(interval: Option<Duration>) -> Meter {
+        let now = Instant::now();
+
+        Meter {
+            interval,
+            start: now,
+            last: (now, 0),
+        }
+    }
+
+    /// Notes that `count` out of `total` are done.
+    ///
+    /// Returns the throughput when progress is to be reported, which it
+    /// always is once done.
+    pub fn update(&mut self, count: u64, total: u64) -> Option<Throughput> {
+        let now = Instant::now();
+        let (time, reported) = self.last;
+
+        let due = match self.interval {
+            Some(interval) => now.duration_since(time) >= interval,
+            None => true,
+        };
+
+        if !due && count < total {
+            return None;
+        }
+
+        let average = rate(count, now.duration_since(self.start));
+        let current = rate(count.saturating_sub(reported), now.duration_since(time));
+
+        let eta = if current > 0.0 {
+            Some(Duration::from_secs_f64(total.saturating_sub(count) as f64 / current))
+        } else {
+            None
+        };
+
+        self.last = (now, count);
+
+        Some(Throughput { current, average, eta })
+    }
+}
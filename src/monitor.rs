@@ -0,0 +1,379 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Hotplug monitoring of block devices.
+//!
+//! The kernel broadcasts a uevent over netlink whenever a device appears,
+//! disappears or changes (e.g. a card is inserted into a reader). Events
+//! are plain text: "action@devpath" followed by "KEY=value" lines, each
+//! terminated by a NUL byte.
+
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::Sender;
+use std::thread::{self,JoinHandle};
+
+use libc;
+
+use device::{Device,DeviceFilter,Sysfs};
+use error::{Error,Result};
+
+/// Multicast group of the events sent by the kernel (1), as opposed to
+/// the ones rebroadcast by udev (2).
+const KERNEL_GROUP: u32 = 1;
+
+/// Large enough for any uevent, whose environment is capped at 2 KiB.
+const EVENT_SIZE: usize = 8192;
+
+/// Change in the set of devices
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    /// A device was plugged in
+    Added(Device),
+    /// The device at this path was unplugged
+    Removed(String),
+    /// A device changed, typically a card being inserted or pulled
+    Changed(Device),
+}
+
+/// Kernel uevent
+#[derive(Debug)]
+struct Uevent {
+    action: String,
+    env: HashMap<String, String>,
+}
+
+impl Uevent {
+    fn parse(message: &[u8]) -> Option<Uevent> {
+        let mut fields = message.split(|byte| *byte == 0)
+            .filter(|field| !field.is_empty())
+            .map(String::from_utf8_lossy);
+
+        // Header: "action@devpath"
+        let header = fields.next()?;
+        let action = header.split('@').next()?.to_owned();
+
+        let env = fields.filter_map(|field| {
+            let mut parts = field.splitn(2, '=');
+            Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+        }).collect();
+
+        Some(Uevent { action, env })
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(String::as_str)
+    }
+}
+
+/// Watches block devices being plugged in and out.
+pub struct DeviceMonitor {
+    socket: RawFd,
+    sysfs: Sysfs,
+    filter: DeviceFilter,
+    /// Paths of the devices reported so far, to tell additions from changes
+    known: HashSet<String>,
+    /// Events found by a rescan, yet to be returned
+    pending: VecDeque<DeviceEvent>,
+}
+
+impl DeviceMonitor {
+    /// Subscribes to kernel uevents.
+    ///
+    /// Devices already present count as known, so that their removal is
+    /// reported, but no event is sent for them.
+    pub fn new() -> Result<DeviceMonitor> {
+        DeviceMonitor::with_sysfs(Sysfs::default(), DeviceFilter::default())
+    }
+
+    /// Subscribes to kernel uevents, describing devices from `sysfs` and
+    /// only reporting the ones matching `filter`.
+    pub fn with_sysfs(sysfs: Sysfs, filter: DeviceFilter) -> Result<DeviceMonitor> {
+        let socket = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+
+        if socket < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+
+        // Closes the socket should binding fail
+        let mut monitor = DeviceMonitor {
+            socket,
+            sysfs,
+            filter,
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+        };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_GROUP;
+
+        let bound = unsafe {
+            libc::bind(
+                socket,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+
+        if bound < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+
+        // Listed after subscribing, so that nothing falls in between
+        if let Ok(devices) = monitor.sysfs.devices(&monitor.filter) {
+            monitor.known = devices.into_iter().map(|device| device.path).collect();
+        }
+
+        Ok(monitor)
+    }
+
+    /// Waits for the next event about a disk matching the filter.
+    pub fn next_event(&mut self) -> Result<DeviceEvent> {
+        let mut buffer = vec![0u8; EVENT_SIZE];
+
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            let mut sender: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut length = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+
+            let n = unsafe {
+                libc::recvfrom(
+                    self.socket,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                    &mut sender as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut length,
+                )
+            };
+
+            if n < 0 {
+                let e = io::Error::last_os_error();
+
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                // Events were dropped while nobody was reading
+                if e.raw_os_error() == Some(libc::ENOBUFS) {
+                    self.rescan();
+                    continue;
+                }
+
+                return Err(Error::Io(e));
+            }
+
+            // Only the kernel is trusted, not other processes
+            if sender.nl_pid != 0 {
+                continue;
+            }
+
+            if let Some(event) = Uevent::parse(&buffer[..n as usize]).and_then(|uevent| self.handle(&uevent)) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Turns a uevent into a device event, if it is relevant.
+    fn handle(&mut self, uevent: &Uevent) -> Option<DeviceEvent> {
+        // Partitions come and go with their disk
+        if uevent.get("SUBSYSTEM") != Some("block") || uevent.get("DEVTYPE") != Some("disk") {
+            return None;
+        }
+
+        let name = uevent.get("DEVNAME")?;
+        let path = format!("/dev/{}", name.trim_start_matches("/dev/"));
+
+        match uevent.action.as_str() {
+            "remove" => {
+                if self.known.remove(&path) {
+                    Some(DeviceEvent::Removed(path))
+                } else {
+                    None
+                }
+            }
+            "add" | "change" => {
+                let device = self.sysfs.device(name.trim_start_matches("/dev/")).ok()?;
+
+                // A card pulled out of a reader may no longer match, e.g. on size
+                if !self.filter.matches(&device) {
+                    return if self.known.remove(&path) { Some(DeviceEvent::Removed(path)) } else { None };
+                }
+
+                if self.known.insert(path) {
+                    Some(DeviceEvent::Added(device))
+                } else {
+                    Some(DeviceEvent::Changed(device))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Queues the events that may have been lost, from the devices present
+    /// now: known ones are reported as changed, as they may have been.
+    fn rescan(&mut self) {
+        let devices = match self.sysfs.devices(&self.filter) {
+            Ok(devices) => devices,
+            Err(_) => return,
+        };
+
+        let present: HashSet<_> = devices.iter().map(|device| device.path.clone()).collect();
+        let mut gone: Vec<_> = self.known.difference(&present).cloned().collect();
+
+        gone.sort();
+        for path in gone {
+            self.known.remove(&path);
+            self.pending.push_back(DeviceEvent::Removed(path));
+        }
+
+        for device in devices {
+            if self.known.insert(device.path.clone()) {
+                self.pending.push_back(DeviceEvent::Added(device));
+            } else {
+                self.pending.push_back(DeviceEvent::Changed(device));
+            }
+        }
+    }
+
+    /// Sends the events over `tx` from a background thread.
+    ///
+    /// The thread stops once the receiver is dropped, after the next event.
+    pub fn spawn(mut self, tx: Sender<DeviceEvent>) -> JoinHandle<Result<()>> {
+        thread::spawn(move || {
+            loop {
+                let event = self.next_event()?;
+
+                if tx.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+        })
+    }
+}
+
+impl Drop for DeviceMonitor {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use device::tests::fixture;
+
+    /// Monitor of the `fixture` devices, without a socket
+    fn monitor(name: &str) -> DeviceMonitor {
+        DeviceMonitor {
+            socket: -1,
+            sysfs: fixture(name),
+            filter: DeviceFilter::default(),
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn uevent(action: &str, name: &str, devtype: &str) -> Uevent {
+        let message = format!("{}@/devices/{}\0ACTION={}\0SUBSYSTEM=block\0DEVNAME={}\0DEVTYPE={}\0",
+                              action, name, action, name, devtype);
+
+        Uevent::parse(message.as_bytes()).unwrap()
+    }
+
+    fn summary(event: Option<DeviceEvent>) -> Option<String> {
+        event.map(|event| match event {
+            DeviceEvent::Added(device) => format!("added {}", device.path),
+            DeviceEvent::Removed(path) => format!("removed {}", path),
+            DeviceEvent::Changed(device) => format!("changed {}", device.path),
+        })
+    }
+
+    #[test]
+    fn uevents_are_parsed() {
+        let uevent = Uevent::parse(b"add@/devices/x/block/sdb\0ACTION=add\0\0DEVNAME=sdb\0SEQNUM=1=2\0junk\0").unwrap();
+
+        assert_eq!(uevent.action, "add");
+        assert_eq!(uevent.get("DEVNAME"), Some("sdb"));
+        assert_eq!(uevent.get("SEQNUM"), Some("1=2"));
+        assert_eq!(uevent.get("junk"), None);
+        assert_eq!(uevent.env.len(), 3);
+
+        assert!(Uevent::parse(b"").is_none());
+        assert!(Uevent::parse(b"\0\0").is_none());
+    }
+
+    #[test]
+    fn disks_are_tracked_from_addition_to_removal() {
+        let mut monitor = monitor("monitor-track");
+
+        assert_eq!(summary(monitor.handle(&uevent("add", "sdb", "disk"))).as_deref(), Some("added /dev/sdb"));
+        assert_eq!(summary(monitor.handle(&uevent("change", "sdb", "disk"))).as_deref(), Some("changed /dev/sdb"));
+        assert_eq!(summary(monitor.handle(&uevent("remove", "sdb", "disk"))).as_deref(), Some("removed /dev/sdb"));
+        assert_eq!(summary(monitor.handle(&uevent("remove", "sdb", "disk"))), None);
+    }
+
+    #[test]
+    fn irrelevant_uevents_are_ignored() {
+        let mut monitor = monitor("monitor-ignore");
+        let mut usb = uevent("add", "sdb", "disk");
+
+        usb.env.insert("SUBSYSTEM".to_owned(), "usb".to_owned());
+
+        assert_eq!(summary(monitor.handle(&usb)), None);
+        assert_eq!(summary(monitor.handle(&uevent("add", "sdb1", "partition"))), None);
+        assert_eq!(summary(monitor.handle(&uevent("add", "nvme0n1", "disk"))), None);
+        assert_eq!(summary(monitor.handle(&uevent("add", "sdz", "disk"))), None);
+        assert_eq!(summary(monitor.handle(&uevent("bind", "sdb", "disk"))), None);
+        assert!(monitor.known.is_empty());
+    }
+
+    #[test]
+    fn devices_no_longer_matching_are_removed() {
+        let mut monitor = monitor("monitor-mismatch");
+
+        monitor.handle(&uevent("add", "mmcblk0", "disk"));
+        monitor.filter.max_size = Some(1 << 30);
+
+        assert_eq!(summary(monitor.handle(&uevent("change", "mmcblk0", "disk"))).as_deref(), Some("removed /dev/mmcblk0"));
+        assert_eq!(summary(monitor.handle(&uevent("change", "mmcblk0", "disk"))), None);
+    }
+
+    #[test]
+    fn rescans_report_what_was_missed() {
+        let mut monitor = monitor("monitor-rescan");
+
+        monitor.known.insert("/dev/sdb".to_owned());
+        monitor.known.insert("/dev/sdx".to_owned());
+        monitor.rescan();
+
+        let mut events: Vec<_> = monitor.pending.drain(..).map(|event| summary(Some(event)).unwrap()).collect();
+        events[1..].sort();
+
+        assert_eq!(events, ["removed /dev/sdx", "added /dev/mmcblk0", "added /dev/sdc", "changed /dev/sdb"]);
+        assert_eq!(monitor.known.len(), 3);
+    }
+}
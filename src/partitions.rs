@@ -0,0 +1,1047 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Partition tables: MBR, with its logical partitions, and GPT.
+//!
+//! A GPT disk starts with a protective MBR, followed by the GPT header in
+//! the second sector and the partition entries. A backup of the header and
+//! of the entries sits in the last sectors of the disk.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read,Seek,SeekFrom,Write};
+use std::path::Path;
+
+use crc32fast;
+
+use blockdev;
+use error::{Error,Result};
+use source;
+
+const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: &[u8] = &[0x05, 0x0f, 0x85];
+/// Sectors MBR partitions are counted in. Disks of 4096 bytes logical sectors
+/// (4Kn) count in theirs instead, which isn't supported: their MBR is refused.
+const MBR_SECTOR: u64 = 512;
+/// Bound on the chain of logical partitions, in case it loops.
+const MAX_LOGICAL: u32 = 128;
+
+pub const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Size of the GPT header covered by its CRC, in revision 1.0
+pub const GPT_HEADER_SIZE: usize = 92;
+/// GPT sector sizes to try on images, whose sector size isn't known.
+const GPT_SECTORS: &[u64] = &[512, 4096];
+/// Bound on the size of the entry array, against corrupted headers.
+const MAX_ENTRIES_SIZE: u64 = 1024 * 1024;
+
+pub fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+pub fn put_le(bytes: &mut [u8], value: u64) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Updates the CRC of a raw GPT header.
+fn seal(sector: &mut [u8]) {
+    let size = le(&sector[12..16]) as usize;
+
+    put_le(&mut sector[16..20], 0);
+    let crc = crc32fast::hash(&sector[..size]);
+    put_le(&mut sector[16..20], crc as u64);
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidPartitionTable(reason)
+}
+
+/// GUID, as stored on disk (mixed endian)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_nil(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+}
+
+/// Formats as "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+
+        write!(f, "{:08X}-{:04X}-{:04X}-", le(&b[0..4]), le(&b[4..6]), le(&b[6..8]))?;
+
+        for byte in &b[8..10] {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        write!(f, "-")?;
+
+        for byte in &b[10..16] {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Type of a partition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR system ID
+    Mbr(u8),
+    /// GPT partition type GUID
+    Gpt(Guid),
+}
+
+impl PartitionType {
+    /// Common name of the type, for the well known ones.
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            PartitionType::Mbr(id) => match id {
+                0x01 | 0x04 | 0x06 | 0x0e => Some("FAT16"),
+                0x0b | 0x0c => Some("FAT32"),
+                0x07 => Some("NTFS/exFAT"),
+                0x05 | 0x0f | 0x85 => Some("Extended"),
+                0x82 => Some("Linux swap"),
+                0x83 => Some("Linux"),
+                0x8e => Some("Linux LVM"),
+                0xef => Some("EFI System"),
+                _ => None,
+            },
+            PartitionType::Gpt(guid) => match guid.to_string().as_str() {
+                "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => Some("EFI System"),
+                "21686148-6449-6E6F-744E-656564454649" => Some("BIOS boot"),
+                "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => Some("Microsoft basic data"),
+                "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => Some("Linux filesystem"),
+                "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => Some("Linux swap"),
+                "E6D6D379-F507-44C2-A23C-238F2A3DF928" => Some("Linux LVM"),
+                "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => Some("Linux root (x86-64)"),
+                "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => Some("Linux root (ARM64)"),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.name(), *self) {
+            (Some(name), _) => f.write_str(name),
+            (None, PartitionType::Mbr(id)) => write!(f, "0x{:02x}", id),
+            (None, PartitionType::Gpt(guid)) => write!(f, "{}", guid),
+        }
+    }
+}
+
+/// Partition of a disk
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// Number, from 1; logical MBR partitions start at 5
+    pub number: u32,
+    /// Offset from the start of the disk, in bytes
+    pub start: u64,
+    /// Size, in bytes
+    pub size: u64,
+    pub kind: PartitionType,
+    /// Unique GUID of a GPT partition
+    pub guid: Option<Guid>,
+    /// Name of a GPT partition
+    pub name: Option<String>,
+    /// 0x80 for a bootable MBR partition, attribute bits for a GPT one
+    pub flags: u64,
+}
+
+impl Partition {
+    /// Offset of the end of the partition, in bytes.
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// GPT header, primary or backup
+#[derive(Clone, Debug)]
+pub struct GptHeader {
+    /// Sector holding this header
+    pub current_lba: u64,
+    /// Sector holding the other header
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    /// First sector of the partition entries
+    pub entries_lba: u64,
+    pub entry_count: u32,
+    pub entry_size: u32,
+    /// CRC32 of the partition entries
+    pub entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Parses a header sector, checking its signature and CRC.
+    pub fn parse(sector: &[u8]) -> Result<GptHeader> {
+        if sector.len() < GPT_HEADER_SIZE || &sector[..8] != GPT_SIGNATURE {
+            return Err(invalid("missing GPT signature".to_owned()));
+        }
+
+        let size = le(&sector[12..16]) as usize;
+
+        if size < GPT_HEADER_SIZE || size > sector.len() {
+            return Err(invalid(format!("bad GPT header size {}", size)));
+        }
+
+        let mut header = sector[..size].to_vec();
+        header[16..20].copy_from_slice(&[0; 4]);
+
+        if crc32fast::hash(&header) as u64 != le(&sector[16..20]) {
+            return Err(invalid("GPT header CRC mismatch".to_owned()));
+        }
+
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&sector[56..72]);
+
+        Ok(GptHeader {
+            current_lba: le(&sector[24..32]),
+            alternate_lba: le(&sector[32..40]),
+            first_usable_lba: le(&sector[40..48]),
+            last_usable_lba: le(&sector[48..56]),
+            disk_guid: Guid(guid),
+            entries_lba: le(&sector[72..80]),
+            entry_count: le(&sector[80..84]) as u32,
+            entry_size: le(&sector[84..88]) as u32,
+            entries_crc: le(&sector[88..92]) as u32,
+        })
+    }
+
+    /// Size of the entry array, in bytes.
+    pub fn entries_size(&self) -> u64 {
+        self.entry_count as u64 * self.entry_size as u64
+    }
+}
+
+/// Partition table of a disk
+#[derive(Clone, Debug)]
+pub enum PartitionTable {
+    Mbr {
+        disk_signature: u32,
+        partitions: Vec<Partition>,
+    },
+    Gpt {
+        /// Size of the sectors the table is expressed in, in bytes
+        sector_size: u64,
+        /// Header in use: the primary one, or the backup if the primary is damaged
+        header: GptHeader,
+        /// Backup header, when found where the primary one says
+        backup: Option<GptHeader>,
+        partitions: Vec<Partition>,
+    },
+}
+
+impl PartitionTable {
+    /// Reads the partition table of a raw image or of a device.
+    ///
+    /// Returns `None` when there is no partition table.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Option<PartitionTable>> {
+        let path = path.as_ref();
+        let mut file = File::open(path).map_err(|e| Error::device_open(&path.to_string_lossy(), e))?;
+        let size = blockdev::file_size(&file)?;
+        let table = PartitionTable::read(&mut file, size)?;
+
+        if let Some(PartitionTable::Mbr { .. }) = table {
+            check_mbr_sectors(blockdev::logical_sector_size(&file)?)?;
+        }
+
+        Ok(table)
+    }
+
+    /// Reads the partition table from `disk`, `size` bytes long.
+    pub fn read<R: Read + Seek>(disk: &mut R, size: u64) -> Result<Option<PartitionTable>> {
+        let mut mbr = [0u8; MBR_SIZE];
+
+        if read_at(disk, 0, &mut mbr)? < MBR_SIZE || mbr[510..] != MBR_SIGNATURE {
+            return Ok(None);
+        }
+
+        let protective = (0..4).any(|i| mbr[MBR_ENTRIES + i * 16 + 4] == MBR_PROTECTIVE);
+
+        if protective {
+            return read_gpt(disk, size).map(Some);
+        }
+
+        Ok(Some(PartitionTable::Mbr {
+            disk_signature: le(&mbr[440..444]) as u32,
+            partitions: read_mbr(disk, &mbr)?.into_iter().map(|(_, partition)| partition).collect(),
+        }))
+    }
+
+    pub fn partitions(&self) -> &[Partition] {
+        match *self {
+            PartitionTable::Mbr { ref partitions, .. } | PartitionTable::Gpt { ref partitions, .. } => partitions,
+        }
+    }
+
+    /// End of the last partition, in bytes.
+    pub fn end(&self) -> Option<u64> {
+        self.partitions().iter().map(Partition::end).max()
+    }
+}
+
+pub fn write_at<W: Write + Seek>(disk: &mut W, offset: u64, buf: &[u8]) -> Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+
+    Ok(disk.write_all(buf)?)
+}
+
+pub fn read_at<R: Read + Seek>(disk: &mut R, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    disk.seek(SeekFrom::Start(offset))?;
+
+    Ok(source::read_full(disk, buf)?)
+}
+
+/// Parses a 16 bytes MBR entry, whose sectors are relative to `base`.
+fn mbr_entry(entry: &[u8], base: u64, number: u32) -> Partition {
+    Partition {
+        number,
+        start: (base + le(&entry[8..12])) * MBR_SECTOR,
+        size: le(&entry[12..16]) * MBR_SECTOR,
+        kind: PartitionType::Mbr(entry[4]),
+        guid: None,
+        name: None,
+        flags: entry[0] as u64,
+    }
+}
+
+/// Lists the primary partitions, then the logical ones, along with the
+/// offset of their entry on disk.
+fn read_mbr<R: Read + Seek>(disk: &mut R, mbr: &[u8]) -> Result<Vec<(u64, Partition)>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+
+    for (i, entry) in mbr[MBR_ENTRIES..510].chunks(16).enumerate() {
+        if entry[4] == 0 {
+            continue;
+        }
+
+        if MBR_EXTENDED.contains(&entry[4]) {
+            extended = Some(le(&entry[8..12]));
+        }
+
+        partitions.push(((MBR_ENTRIES + i * 16) as u64, mbr_entry(entry, 0, i as u32 + 1)));
+    }
+
+    // Each EBR holds a logical partition, relative to the EBR, and the next
+    // EBR, relative to the extended partition.
+    if let Some(first) = extended {
+        let mut ebr = first;
+        let mut number = 5;
+        let mut sector = [0u8; MBR_SIZE];
+
+        while number < 5 + MAX_LOGICAL {
+            if read_at(disk, ebr * MBR_SECTOR, &mut sector)? < MBR_SIZE || sector[510..] != MBR_SIGNATURE {
+                return Err(invalid(format!("bad extended boot record at sector {}", ebr)));
+            }
+
+            let logical = &sector[MBR_ENTRIES..MBR_ENTRIES + 16];
+            let next = &sector[MBR_ENTRIES + 16..MBR_ENTRIES + 32];
+
+            if logical[4] != 0 {
+                partitions.push((ebr * MBR_SECTOR + MBR_ENTRIES as u64, mbr_entry(logical, ebr, number)));
+                number += 1;
+            }
+
+            if next[4] == 0 || le(&next[8..12]) == 0 {
+                break;
+            }
+
+            ebr = first + le(&next[8..12]);
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Refuses MBR partition tables of disks whose sectors aren't `MBR_SECTOR` long.
+pub fn check_mbr_sectors(sector_size: u64) -> Result<()> {
+    if sector_size != MBR_SECTOR {
+        return Err(invalid(format!("MBR partition tables of {} bytes sectors are unsupported", sector_size)));
+    }
+
+    Ok(())
+}
+
+/// Reads the header at `lba` and its entries, checking both CRCs.
+fn read_gpt_copy<R: Read + Seek>(disk: &mut R, sector_size: u64, lba: u64) -> Result<(GptHeader, Vec<u8>)> {
+    let mut sector = vec![0u8; sector_size as usize];
+
+    if read_at(disk, lba * sector_size, &mut sector)? < sector.len() {
+        return Err(invalid(format!("GPT header at sector {} out of the disk", lba)));
+    }
+
+    let header = GptHeader::parse(&sector)?;
+
+    if header.current_lba != lba {
+        return Err(invalid(format!("GPT header at sector {} claims to be at {}", lba, header.current_lba)));
+    }
+
+    if header.entry_size < 128 || header.entries_size() > MAX_ENTRIES_SIZE {
+        return Err(invalid("bad GPT entry array size".to_owned()));
+    }
+
+    let mut entries = vec![0u8; header.entries_size() as usize];
+
+    if read_at(disk, header.entries_lba * sector_size, &mut entries)? < entries.len() {
+        return Err(invalid("GPT entries out of the disk".to_owned()));
+    }
+
+    if crc32fast::hash(&entries) != header.entries_crc {
+        return Err(invalid("GPT entries CRC mismatch".to_owned()));
+    }
+
+    Ok((header, entries))
+}
+
+/// Reads the GPT, falling back to the backup when the primary is damaged.
+fn read_gpt<R: Read + Seek>(disk: &mut R, size: u64) -> Result<PartitionTable> {
+    let mut error = invalid("no GPT header found".to_owned());
+
+    for &sector_size in GPT_SECTORS {
+        let mut signature = [0u8; 8];
+
+        if read_at(disk, sector_size, &mut signature)? < signature.len() || signature != GPT_SIGNATURE {
+            continue;
+        }
+
+        let (header, entries, backup) = match read_gpt_copy(disk, sector_size, 1) {
+            Ok((header, entries)) => {
+                let backup = read_gpt_copy(disk, sector_size, header.alternate_lba).ok().map(|(backup, _)| backup);
+                (header, entries, backup)
+            }
+            Err(e) => match read_gpt_copy(disk, sector_size, size / sector_size - 1) {
+                Ok((backup, entries)) => (backup, entries, None),
+                Err(_) => {
+                    error = e;
+                    continue;
+                }
+            },
+        };
+
+        let partitions = entries.chunks(header.entry_size as usize).enumerate().filter_map(|(i, entry)| {
+            let mut kind = [0u8; 16];
+            let mut guid = [0u8; 16];
+
+            kind.copy_from_slice(&entry[0..16]);
+            guid.copy_from_slice(&entry[16..32]);
+
+            // Unused entries have a nil type
+            if Guid(kind).is_nil() {
+                return None;
+            }
+
+            let first = le(&entry[32..40]);
+            let last = le(&entry[40..48]);
+
+            // The name is UTF-16LE, padded with zeros
+            let name: Vec<u16> = entry[56..128].chunks(2)
+                .map(|unit| unit[0] as u16 | (unit[1] as u16) << 8)
+                .take_while(|unit| *unit != 0)
+                .collect();
+
+            Some(Partition {
+                number: i as u32 + 1,
+                start: first * sector_size,
+                size: (last + 1).saturating_sub(first) * sector_size,
+                kind: PartitionType::Gpt(Guid(kind)),
+                guid: Some(Guid(guid)),
+                name: Some(String::from_utf16_lossy(&name)).filter(|name| !name.is_empty()),
+                flags: le(&entry[48..56]),
+            })
+        }).collect();
+
+        return Ok(PartitionTable::Gpt { sector_size, header, backup, partitions });
+    }
+
+    Err(error)
+}
+
+/// Makes the protective MBR entry of `disk` cover it up to `last_lba`, as
+/// far as its 32 bits sector count allows.
+fn cover_protective_mbr<D: Read + Write + Seek>(disk: &mut D, last_lba: u64) -> Result<()> {
+    let mut mbr = [0u8; MBR_SIZE];
+
+    read_at(disk, 0, &mut mbr)?;
+
+    for entry in mbr[MBR_ENTRIES..510].chunks_mut(16).filter(|entry| entry[4] == MBR_PROTECTIVE) {
+        let start = le(&entry[8..12]);
+
+        put_le(&mut entry[12..16], (last_lba + 1).saturating_sub(start).min(u32::MAX as u64));
+    }
+
+    write_at(disk, 0, &mbr)
+}
+
+/// Moves the backup GPT to the last sectors of `disk`, `size` bytes long.
+///
+/// An image written to a larger device leaves its backup header and entries
+/// where the image ended. The primary header is updated to point to the new
+/// backup and, like the backup, to extend the usable space to the end of the
+/// device, as is the protective MBR. The old backup header is wiped. Returns
+/// `false` when there is no GPT, or when it already ends with the device.
+///
+/// `disk` may also be smaller than the GPT says, as long as the backup fits
+/// after the last partition: the usable space then shrinks.
+pub fn relocate_gpt_backup<D: Read + Write + Seek>(disk: &mut D, size: u64) -> Result<bool> {
+    let table = match PartitionTable::read(disk, size)? {
+        Some(table) => table,
+        None => return Ok(false),
+    };
+
+    let (sector_size, header) = match table {
+        PartitionTable::Gpt { sector_size, ref header, .. } => (sector_size, header.clone()),
+        PartitionTable::Mbr { .. } => return Ok(false),
+    };
+
+    // The entries are the same in both copies, rebuilding from the backup alone isn't worth it
+    if header.current_lba != 1 {
+        return Err(invalid("primary GPT header is damaged".to_owned()));
+    }
+
+    let sectors = size / sector_size;
+    let last_lba = sectors.saturating_sub(1);
+
+    if header.alternate_lba == last_lba {
+        return Ok(false);
+    }
+
+    let entries_sectors = header.entries_size().div_ceil(sector_size);
+
+    // The backup entries come after the primary header, at least
+    let entries_lba = match sectors.checked_sub(entries_sectors + 1) {
+        Some(lba) if lba > 1 => lba,
+        _ => return Err(invalid(format!("device of {} sectors too small for the GPT backup", sectors))),
+    };
+
+    let end = table.end().unwrap_or(0).max(header.first_usable_lba * sector_size);
+
+    if entries_lba * sector_size < end {
+        return Err(invalid(format!("device too small for the GPT backup at sector {}", entries_lba)));
+    }
+
+    let mut entries = vec![0u8; (entries_sectors * sector_size) as usize];
+    let mut primary = vec![0u8; sector_size as usize];
+
+    read_at(disk, header.entries_lba * sector_size, &mut entries)?;
+    read_at(disk, sector_size, &mut primary)?;
+
+    put_le(&mut primary[32..40], last_lba);
+    put_le(&mut primary[48..56], entries_lba - 1);
+    seal(&mut primary);
+
+    let mut backup = primary.clone();
+
+    put_le(&mut backup[24..32], last_lba);
+    put_le(&mut backup[32..40], 1);
+    put_le(&mut backup[72..80], entries_lba);
+    seal(&mut backup);
+
+    // The new backup goes first, a valid GPT stays on disk throughout
+    write_at(disk, entries_lba * sector_size, &entries)?;
+    write_at(disk, last_lba * sector_size, &backup)?;
+    write_at(disk, sector_size, &primary)?;
+    cover_protective_mbr(disk, last_lba)?;
+
+    if header.alternate_lba < last_lba {
+        write_at(disk, header.alternate_lba * sector_size, &vec![0u8; sector_size as usize])?;
+    }
+
+    disk.flush()?;
+
+    Ok(true)
+}
+
+/// Grows the last partition of `disk`, `size` bytes long, to the end of it.
+///
+/// The new end is rounded down to a multiple of `alignment` bytes. On a GPT
+/// disk, the backup is first moved to the end, see `relocate_gpt_backup`. A
+/// logical MBR partition grows along with its extended partition, and with
+/// the link to its EBR, on disks of `sector_size` bytes sectors. Returns
+/// the grown partition, or `None` when there is no partition table or no
+/// room to grow.
+pub fn expand_last_partition<D: Read + Write + Seek>(disk: &mut D, size: u64, sector_size: u64, alignment: u64)
+    -> Result<Option<Partition>>
+{
+    let mut mbr = [0u8; MBR_SIZE];
+
+    if read_at(disk, 0, &mut mbr)? < MBR_SIZE || mbr[510..] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+
+    let protective = (0..4).any(|i| mbr[MBR_ENTRIES + i * 16 + 4] == MBR_PROTECTIVE);
+
+    let grown = if protective {
+        expand_gpt(disk, size, alignment)?
+    } else {
+        check_mbr_sectors(sector_size)?;
+        expand_mbr(disk, &mbr, size, alignment)?
+    };
+
+    disk.flush()?;
+
+    Ok(grown)
+}
+
+fn is_extended(partition: &Partition) -> bool {
+    matches!(partition.kind, PartitionType::Mbr(id) if MBR_EXTENDED.contains(&id))
+}
+
+fn expand_mbr<D: Read + Write + Seek>(disk: &mut D, mbr: &[u8], size: u64, alignment: u64)
+    -> Result<Option<Partition>>
+{
+    let partitions = read_mbr(disk, mbr)?;
+    let extended = partitions.iter().find(|(_, partition)| is_extended(partition));
+
+    let (offset, mut last) = match partitions.iter().filter(|(_, partition)| !is_extended(partition))
+        .max_by_key(|(_, partition)| partition.end())
+    {
+        Some((offset, partition)) => (*offset, partition.clone()),
+        None => return Ok(None),
+    };
+
+    // A logical partition can't leave its extended partition, whose size is
+    // limited too: sizes are 32 bits sector counts
+    let extended = extended.filter(|_| last.number >= 5);
+    let base = extended.map_or(last.start, |(_, extended)| extended.start);
+    let end = (size / alignment * alignment).min(base + u32::MAX as u64 * MBR_SECTOR);
+
+    if end <= last.end() {
+        return Ok(None);
+    }
+
+    let mut count = [0u8; 4];
+
+    if let Some((offset, extended)) = extended {
+        if extended.end() < end {
+            put_le(&mut count, (end - extended.start) / MBR_SECTOR);
+            write_at(disk, offset + 12, &count)?;
+        }
+    }
+
+    // The EBR before the one of a logical partition links to it with a size
+    // spanning up to the end of the partition too
+    let previous = partitions.iter()
+        .find(|(_, partition)| partition.number >= 5 && partition.number + 1 == last.number);
+
+    if let (Some((_, extended)), Some((previous, _))) = (extended, previous) {
+        let ebr = offset - MBR_ENTRIES as u64;
+        let mut link = [0u8; 16];
+
+        read_at(disk, previous + 16, &mut link)?;
+
+        if extended.start + le(&link[8..12]) * MBR_SECTOR == ebr {
+            put_le(&mut count, (end - ebr) / MBR_SECTOR);
+            write_at(disk, previous + 16 + 12, &count)?;
+        }
+    }
+
+    put_le(&mut count, (end - last.start) / MBR_SECTOR);
+    write_at(disk, offset + 12, &count)?;
+
+    last.size = end - last.start;
+
+    Ok(Some(last))
+}
+
+fn expand_gpt<D: Read + Write + Seek>(disk: &mut D, size: u64, alignment: u64) -> Result<Option<Partition>> {
+    // The usable space only reaches the end of the device once the backup is there
+    relocate_gpt_backup(disk, size)?;
+
+    let (sector_size, header, backup, partitions) = match PartitionTable::read(disk, size)? {
+        Some(PartitionTable::Gpt { sector_size, header, backup: Some(backup), partitions }) => {
+            (sector_size, header, backup, partitions)
+        }
+        _ => return Err(invalid("GPT backup not found".to_owned())),
+    };
+
+    let mut last = match partitions.into_iter().max_by_key(Partition::end) {
+        Some(partition) => partition,
+        None => return Ok(None),
+    };
+
+    let end = (header.last_usable_lba + 1) * sector_size / alignment * alignment;
+
+    if end <= last.end() {
+        return Ok(None);
+    }
+
+    let mut entries = vec![0u8; header.entries_size() as usize];
+    let entry = (last.number - 1) as usize * header.entry_size as usize;
+
+    read_at(disk, header.entries_lba * sector_size, &mut entries)?;
+    put_le(&mut entries[entry + 40..entry + 48], end / sector_size - 1);
+
+    let crc = crc32fast::hash(&entries);
+
+    // Backup first, as when relocating it
+    for copy in &[backup, header] {
+        let mut sector = vec![0u8; sector_size as usize];
+
+        read_at(disk, copy.current_lba * sector_size, &mut sector)?;
+        put_le(&mut sector[88..92], crc as u64);
+        seal(&mut sector);
+
+        write_at(disk, copy.entries_lba * sector_size, &entries)?;
+        write_at(disk, copy.current_lba * sector_size, &sector)?;
+    }
+
+    last.size = end - last.start;
+
+    Ok(Some(last))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const SECTOR: u64 = 512;
+    /// "Linux filesystem", as stored on disk
+    const LINUX: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+    ];
+
+    /// 16 bytes MBR entry
+    fn mbr_entry_bytes(kind: u8, start: u64, count: u64) -> Vec<u8> {
+        let mut entry = vec![0u8; 16];
+
+        entry[4] = kind;
+        put_le(&mut entry[8..12], start);
+        put_le(&mut entry[12..16], count);
+        entry
+    }
+
+    /// Boot sector holding `entries`, as (type, first sector, sector count)
+    fn boot_sector(entries: &[(u8, u64, u64)]) -> Vec<u8> {
+        let mut sector = vec![0u8; MBR_SIZE];
+
+        for (i, &(kind, start, count)) in entries.iter().enumerate() {
+            sector[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16]
+                .copy_from_slice(&mbr_entry_bytes(kind, start, count));
+        }
+
+        sector[510..].copy_from_slice(&MBR_SIGNATURE);
+        sector
+    }
+
+    /// GPT disk of `sectors` 512 bytes sectors, with 128 entries of 128
+    /// bytes and Linux partitions given as (first, last, name)
+    fn gpt_disk(sectors: u64, partitions: &[(u64, u64, &str)]) -> Vec<u8> {
+        let mut disk = vec![0u8; (sectors * SECTOR) as usize];
+        let mut entries = vec![0u8; 128 * 128];
+
+        for (i, &(first, last, name)) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+
+            entry[0..16].copy_from_slice(&LINUX);
+            entry[16] = i as u8 + 1;
+            put_le(&mut entry[32..40], first);
+            put_le(&mut entry[40..48], last);
+
+            for (j, unit) in name.encode_utf16().enumerate() {
+                put_le(&mut entry[56 + j * 2..58 + j * 2], unit as u64);
+            }
+        }
+
+        let mut header = vec![0u8; SECTOR as usize];
+
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        put_le(&mut header[8..12], 0x0001_0000);
+        put_le(&mut header[12..16], GPT_HEADER_SIZE as u64);
+        put_le(&mut header[24..32], 1);
+        put_le(&mut header[32..40], sectors - 1);
+        put_le(&mut header[40..48], 34);
+        put_le(&mut header[48..56], sectors - 34);
+        header[56..72].copy_from_slice(&[0x42; 16]);
+        put_le(&mut header[72..80], 2);
+        put_le(&mut header[80..84], 128);
+        put_le(&mut header[84..88], 128);
+        put_le(&mut header[88..92], crc32fast::hash(&entries) as u64);
+        seal(&mut header);
+
+        let mut backup = header.clone();
+
+        put_le(&mut backup[24..32], sectors - 1);
+        put_le(&mut backup[32..40], 1);
+        put_le(&mut backup[72..80], sectors - 33);
+        seal(&mut backup);
+
+        let at = |lba: u64| (lba * SECTOR) as usize;
+
+        disk[..MBR_SIZE].copy_from_slice(&boot_sector(&[(MBR_PROTECTIVE, 1, sectors - 1)]));
+        disk[at(1)..at(2)].copy_from_slice(&header);
+        disk[at(2)..at(34)].copy_from_slice(&entries);
+        disk[at(sectors - 33)..at(sectors - 1)].copy_from_slice(&entries);
+        disk[at(sectors - 1)..].copy_from_slice(&backup);
+        disk
+    }
+
+    /// Sector count of the first entry of the boot sector
+    fn first_entry_count(disk: &[u8]) -> u64 {
+        le(&disk[MBR_ENTRIES + 12..MBR_ENTRIES + 16])
+    }
+
+    /// MBR disk of 8192 sectors with a primary partition, and two logical
+    /// ones in an extended partition
+    fn mbr_disk() -> Vec<u8> {
+        let mut disk = vec![0u8; (8192 * SECTOR) as usize];
+        let mut write = |lba: u64, sector: Vec<u8>| {
+            disk[(lba * SECTOR) as usize..((lba + 1) * SECTOR) as usize].copy_from_slice(&sector);
+        };
+
+        let mut mbr = boot_sector(&[(0x0c, 63, 1985), (0x05, 2048, 2048)]);
+        put_le(&mut mbr[440..444], 0xdead_beef);
+        mbr[MBR_ENTRIES] = 0x80;
+
+        write(0, mbr);
+        write(2048, boot_sector(&[(0x83, 63, 100), (0x05, 200, 163)]));
+        write(2248, boot_sector(&[(0x82, 63, 100)]));
+        disk
+    }
+
+    fn read(disk: &[u8]) -> Result<Option<PartitionTable>> {
+        PartitionTable::read(&mut Cursor::new(disk), disk.len() as u64)
+    }
+
+    #[test]
+    fn mbr_lists_primary_then_logical_partitions() {
+        let (disk_signature, partitions) = match read(&mbr_disk()).unwrap() {
+            Some(PartitionTable::Mbr { disk_signature, partitions }) => (disk_signature, partitions),
+            table => panic!("unexpected table {:?}", table),
+        };
+
+        assert_eq!(disk_signature, 0xdead_beef);
+
+        let layout: Vec<_> = partitions.iter().map(|p| (p.number, p.start / SECTOR, p.size / SECTOR)).collect();
+        assert_eq!(layout, [(1, 63, 1985), (2, 2048, 2048), (5, 2048 + 63, 100), (6, 2248 + 63, 100)]);
+
+        assert_eq!(partitions[0].flags, 0x80);
+        assert_eq!(partitions[0].kind.to_string(), "FAT32");
+        assert_eq!(partitions[1].kind.to_string(), "Extended");
+        assert_eq!(partitions[3].kind, PartitionType::Mbr(0x82));
+    }
+
+    #[test]
+    fn broken_ebr_chains_are_refused() {
+        let mut disk = mbr_disk();
+        disk[(2248 * SECTOR) as usize + 510] = 0;
+
+        assert!(matches!(read(&disk), Err(Error::InvalidPartitionTable(_))));
+    }
+
+    #[test]
+    fn gpt_partitions_are_read() {
+        let disk = gpt_disk(4096, &[(34, 999, "root"), (1000, 2047, "données")]);
+
+        let (sector_size, header, backup, partitions) = match read(&disk).unwrap() {
+            Some(PartitionTable::Gpt { sector_size, header, backup, partitions }) => {
+                (sector_size, header, backup, partitions)
+            }
+            table => panic!("unexpected table {:?}", table),
+        };
+
+        assert_eq!(sector_size, 512);
+        assert_eq!(header.current_lba, 1);
+        assert_eq!(header.disk_guid, Guid([0x42; 16]));
+        assert_eq!(backup.map(|backup| backup.current_lba), Some(4095));
+
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].start, 34 * SECTOR);
+        assert_eq!(partitions[0].size, 966 * SECTOR);
+        assert_eq!(partitions[1].end(), 2048 * SECTOR);
+        assert_eq!(partitions[1].number, 2);
+        assert_eq!(partitions[0].kind.to_string(), "Linux filesystem");
+        assert_eq!(partitions[0].guid.unwrap().to_string(), "00000001-0000-0000-0000-000000000000");
+        assert_eq!(partitions[1].name.as_deref(), Some("données"));
+    }
+
+    #[test]
+    fn gpt_names_are_utf16() {
+        let disk = gpt_disk(4096, &[(34, 999, "\u{1d53c}FI ☃"), (1000, 2047, "")]);
+        let table = read(&disk).unwrap().unwrap();
+
+        // Outside the BMP, as a surrogate pair
+        assert_eq!(table.partitions()[0].name.as_deref(), Some("\u{1d53c}FI ☃"));
+        assert_eq!(table.partitions()[1].name, None);
+    }
+
+    #[test]
+    fn damaged_primary_gpt_falls_back_to_the_backup() {
+        let mut disk = gpt_disk(4096, &[(34, 999, "root")]);
+        // Header CRC mismatch
+        disk[SECTOR as usize + 60] ^= 0xff;
+
+        match read(&disk).unwrap() {
+            Some(PartitionTable::Gpt { header, backup: None, partitions, .. }) => {
+                assert_eq!(header.current_lba, 4095);
+                assert_eq!(partitions[0].name.as_deref(), Some("root"));
+            }
+            table => panic!("unexpected table {:?}", table),
+        }
+    }
+
+    #[test]
+    fn gpt_entries_must_match_their_crc() {
+        let mut disk = gpt_disk(4096, &[(34, 999, "root")]);
+
+        // Primary entries: the backup ones are used instead
+        disk[2 * SECTOR as usize + 40] ^= 0xff;
+
+        match read(&disk).unwrap() {
+            Some(PartitionTable::Gpt { header, partitions, .. }) => {
+                assert_eq!(header.current_lba, 4095);
+                assert_eq!(partitions[0].end(), 1000 * SECTOR);
+            }
+            table => panic!("unexpected table {:?}", table),
+        }
+
+        // Both copies
+        disk[(4096 - 33) * SECTOR as usize + 40] ^= 0xff;
+
+        assert!(matches!(read(&disk), Err(Error::InvalidPartitionTable(_))));
+    }
+
+    #[test]
+    fn relocating_the_gpt_backup_covers_the_device() {
+        let mut disk = gpt_disk(2048, &[(2048 - 34 - 100, 2048 - 35, "root")]);
+        disk.resize(4096 * SECTOR as usize, 0);
+
+        let mut disk = Cursor::new(disk);
+
+        assert!(relocate_gpt_backup(&mut disk, 4096 * SECTOR).unwrap());
+        assert_eq!(first_entry_count(disk.get_ref()), 4095);
+
+        match PartitionTable::read(&mut disk, 4096 * SECTOR).unwrap() {
+            Some(PartitionTable::Gpt { header, backup: Some(backup), .. }) => {
+                assert_eq!(header.alternate_lba, 4095);
+                assert_eq!(header.last_usable_lba, 4096 - 34);
+                assert_eq!(backup.current_lba, 4095);
+            }
+            table => panic!("unexpected table {:?}", table),
+        }
+
+        // Already at the end
+        assert!(!relocate_gpt_backup(&mut disk, 4096 * SECTOR).unwrap());
+    }
+
+    #[test]
+    fn the_gpt_backup_may_move_down_to_the_last_partition() {
+        let mut disk = Cursor::new(gpt_disk(4096, &[(34, 999, "root")]));
+
+        assert!(relocate_gpt_backup(&mut disk, 1033 * SECTOR).unwrap());
+
+        let mut image = Cursor::new(disk.get_ref()[..(1033 * SECTOR) as usize].to_vec());
+
+        match PartitionTable::read(&mut image, 1033 * SECTOR).unwrap() {
+            Some(PartitionTable::Gpt { header, backup: Some(backup), partitions, .. }) => {
+                assert_eq!(header.alternate_lba, 1032);
+                assert_eq!(header.last_usable_lba, 999);
+                assert_eq!(backup.entries_lba, 1000);
+                assert_eq!(partitions.len(), 1);
+            }
+            table => panic!("unexpected table {:?}", table),
+        }
+        assert_eq!(first_entry_count(image.get_ref()), 1032);
+
+        // One sector short
+        let mut disk = Cursor::new(gpt_disk(4096, &[(34, 999, "root")]));
+        assert!(relocate_gpt_backup(&mut disk, 1032 * SECTOR).is_err());
+    }
+
+    #[test]
+    fn the_gpt_backup_entries_must_fit_in_the_device() {
+        let mut disk = Cursor::new(gpt_disk(2048, &[]));
+
+        match relocate_gpt_backup(&mut disk, 20 * SECTOR) {
+            Err(Error::InvalidPartitionTable(reason)) => {
+                assert_eq!(reason, "device of 20 sectors too small for the GPT backup");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn expanding_a_gpt_partition_covers_the_device() {
+        let mut disk = gpt_disk(2048, &[(34, 1000, "root")]);
+        disk.resize(4096 * SECTOR as usize, 0);
+
+        let mut disk = Cursor::new(disk);
+        let grown = expand_last_partition(&mut disk, 4096 * SECTOR, SECTOR, SECTOR).unwrap().unwrap();
+
+        assert_eq!(grown.end(), (4096 - 33) * SECTOR);
+        assert_eq!(first_entry_count(disk.get_ref()), 4095);
+
+        let table = PartitionTable::read(&mut disk, 4096 * SECTOR).unwrap().unwrap();
+        assert_eq!(table.end(), Some((4096 - 33) * SECTOR));
+    }
+
+    #[test]
+    fn expanding_a_logical_partition_grows_its_links() {
+        let mut disk = Cursor::new(mbr_disk());
+        let grown = expand_last_partition(&mut disk, 8192 * SECTOR, SECTOR, SECTOR).unwrap().unwrap();
+
+        assert_eq!(grown.number, 6);
+        assert_eq!(grown.end(), 8192 * SECTOR);
+
+        let sector = |lba: u64| &disk.get_ref()[(lba * SECTOR) as usize..((lba + 1) * SECTOR) as usize];
+        let count = |lba: u64, entry: usize| {
+            let entry = MBR_ENTRIES + entry * 16;
+
+            le(&sector(lba)[entry + 12..entry + 16])
+        };
+
+        assert_eq!(count(0, 1), 8192 - 2048);
+        assert_eq!(count(2048, 0), 100);
+        assert_eq!(count(2048, 1), 8192 - 2248);
+        assert_eq!(count(2248, 0), 8192 - 2248 - 63);
+    }
+
+    #[test]
+    fn mbr_of_4kn_disks_is_refused() {
+        let mut disk = Cursor::new(mbr_disk());
+
+        match expand_last_partition(&mut disk, 8192 * SECTOR, 4096, SECTOR) {
+            Err(Error::InvalidPartitionTable(reason)) => {
+                assert_eq!(reason, "MBR partition tables of 4096 bytes sectors are unsupported");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(disk.get_ref(), &mbr_disk());
+    }
+
+    #[test]
+    fn protective_mbr_is_bounded_to_32_bits() {
+        let mut disk = Cursor::new(boot_sector(&[(MBR_PROTECTIVE, 1, 2047), (0x83, 2048, 100)]));
+
+        cover_protective_mbr(&mut disk, 1 << 33).unwrap();
+
+        assert_eq!(first_entry_count(disk.get_ref()), u32::MAX as u64);
+        assert_eq!(le(&disk.get_ref()[MBR_ENTRIES + 28..MBR_ENTRIES + 32]), 100);
+    }
+}
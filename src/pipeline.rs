@@ -0,0 +1,311 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Overlapped reading and writing.
+//!
+//! The device is written from a dedicated thread, fed through a bounded
+//! queue of buffers: while one buffer is being written, the next ones are
+//! read and decompressed by the caller. Written buffers are handed back to
+//! be filled again, so that no allocation happens past the first few.
+
+use std::fs::File;
+use std::io::{Write,Seek,SeekFrom,ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::sync::mpsc::{self,Receiver,SyncSender};
+use std::thread::{self,JoinHandle};
+
+use error::{Error,Result};
+
+/// Number of buffers in flight: one being written, the others being filled.
+pub const DEPTH: usize = 3;
+
+/// Writes the whole buffer to the device, failing on short writes.
+fn write_chunk(device: &mut File, buffer: &[u8], offset: u64) -> Result<()> {
+    let mut written = 0;
+
+    while written < buffer.len() {
+        match device.write(&buffer[written..]) {
+            Ok(0) => {
+                return Err(Error::ShortWrite {
+                    offset,
+                    expected: buffer.len(),
+                    written,
+                });
+            }
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(Error::Write {
+                    offset: offset + written as u64,
+                    cause: e,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Data to write at some offset of the device
+struct Job {
+    offset: u64,
+    buffer: Vec<u8>,
+    length: usize,
+}
+
+/// Writes to a device from a background thread.
+pub struct Writer {
+    jobs: Option<SyncSender<Job>>,
+    done: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<Result<File>>>,
+    written: Arc<AtomicU64>,
+    buffer_size: usize,
+    allocated: usize,
+}
+
+impl Writer {
+    /// Starts writing to `device`.
+    ///
+    /// The device is flushed once all the data has been written, and also
+    /// every `sync_interval` bytes when given.
+    pub fn new(mut device: File, buffer_size: usize, sync_interval: Option<u64>) -> Writer {
+        let (jobs, queue) = mpsc::sync_channel::<Job>(DEPTH - 1);
+        let (recycle, done) = mpsc::channel();
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+
+        let thread = thread::spawn(move || {
+            let mut position = 0;
+            let mut unsynced = 0;
+
+            for job in queue {
+                if job.offset != position {
+                    device.seek(SeekFrom::Start(job.offset))
+                        .map_err(|e| Error::Write { offset: job.offset, cause: e })?;
+                }
+
+                write_chunk(&mut device, &job.buffer[..job.length], job.offset)?;
+
+                position = job.offset + job.length as u64;
+                unsynced += job.length as u64;
+
+                if sync_interval.is_some_and(|interval| unsynced >= interval) {
+                    device.sync_data().map_err(|e| Error::Write { offset: position, cause: e })?;
+                    unsynced = 0;
+                }
+
+                counter.fetch_add(job.length as u64, Ordering::SeqCst);
+
+                // The caller may have stopped asking for buffers
+                let _ = recycle.send(job.buffer);
+            }
+
+            device.sync_data().map_err(|e| Error::Write { offset: position, cause: e })?;
+
+            Ok(device)
+        });
+
+        Writer {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+            written,
+            buffer_size,
+            allocated: 0,
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    /// Gets a buffer to fill, waiting for one to be written if need be.
+    ///
+    /// Its content is whatever was last written from it.
+    pub fn buffer(&mut self) -> Result<Vec<u8>> {
+        if self.allocated < DEPTH {
+            self.allocated += 1;
+            return Ok(vec![0u8; self.buffer_size]);
+        }
+
+        match self.done.recv() {
+            Ok(buffer) => Ok(buffer),
+            Err(_) => Err(self.failure()),
+        }
+    }
+
+    /// Queues the first `length` bytes of `buffer` to be written at `offset`.
+    pub fn write(&mut self, offset: u64, buffer: Vec<u8>, length: usize) -> Result<()> {
+        let sent = match self.jobs {
+            Some(ref jobs) => jobs.send(Job { offset, buffer, length }).is_ok(),
+            None => false,
+        };
+
+        if sent { Ok(()) } else { Err(self.failure()) }
+    }
+
+    /// Waits for all the data to be written and flushed.
+    ///
+    /// Gives the device back, along with the number of bytes written.
+    pub fn finish(mut self) -> Result<(File, u64)> {
+        self.jobs = None;
+
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result.map(|device| (device, self.written())),
+            _ => Err(Error::Io(ErrorKind::Other.into())),
+        }
+    }
+
+    /// Error which stopped the writing thread.
+    fn failure(&mut self) -> Error {
+        self.jobs = None;
+
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => e,
+            _ => Error::Io(ErrorKind::Other.into()),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Let the thread drain the queue rather than leave it dangling
+        self.jobs = None;
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self,create_dir_all,remove_dir_all};
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Empty directory of fixtures, unique to the test `name`
+    fn fixtures(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("acetylene-pipeline-{}-{}", name, process::id()));
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Queues `data` at `offset`, from a buffer of the writer.
+    fn queue(writer: &mut Writer, offset: u64, data: &[u8]) -> Result<*const u8> {
+        let mut buffer = writer.buffer()?;
+        let address = buffer.as_ptr();
+
+        buffer[..data.len()].copy_from_slice(data);
+        writer.write(offset, buffer, data.len())?;
+
+        Ok(address)
+    }
+
+    #[test]
+    fn data_lands_at_its_offset() {
+        let root = fixtures("offsets");
+        let path = root.join("device.img");
+        let mut writer = Writer::new(File::create(&path).unwrap(), 16, Some(4));
+
+        queue(&mut writer, 0, b"head").unwrap();
+        queue(&mut writer, 4, b"-next").unwrap();
+        queue(&mut writer, 20, b"gap").unwrap();
+        queue(&mut writer, 2, b"ov").unwrap();
+
+        let (device, written) = writer.finish().unwrap();
+        drop(device);
+        let content = fs::read(&path).unwrap();
+
+        let _ = remove_dir_all(&root);
+        assert_eq!(written, 14);
+        assert_eq!(content, b"heov-next\0\0\0\0\0\0\0\0\0\0\0gap");
+    }
+
+    #[test]
+    fn buffers_are_recycled_once_written() {
+        let root = fixtures("recycle");
+        let mut writer = Writer::new(File::create(root.join("device.img")).unwrap(), 8, None);
+        let mut addresses = vec![];
+
+        for i in 0..4 * DEPTH {
+            addresses.push(queue(&mut writer, i as u64 * 8, &[i as u8; 8]).unwrap());
+        }
+
+        // Handed back with what was last written from them
+        let buffer = writer.buffer().unwrap();
+        let last = writer.finish();
+
+        let _ = remove_dir_all(&root);
+        let allocated = &addresses[..DEPTH];
+        assert!(addresses.iter().all(|address| allocated.contains(address)));
+        assert!(allocated.contains(&buffer.as_ptr()));
+        assert!(buffer[0] < 4 * DEPTH as u8 && buffer.iter().all(|byte| *byte == buffer[0]));
+        assert_eq!(last.unwrap().1, 4 * DEPTH as u64 * 8);
+    }
+
+    #[test]
+    fn everything_is_flushed_when_finishing() {
+        let root = fixtures("flush");
+        let path = root.join("device.img");
+        let mut writer = Writer::new(File::create(&path).unwrap(), 4096, None);
+
+        for i in 0..64u64 {
+            queue(&mut writer, i * 4096, &[i as u8; 4096]).unwrap();
+        }
+
+        let (device, written) = writer.finish().unwrap();
+        let length = device.metadata().unwrap().len();
+        let content = fs::read(&path).unwrap();
+
+        let _ = remove_dir_all(&root);
+        assert_eq!((written, length), (64 * 4096, 64 * 4096));
+        assert!(content.chunks(4096).enumerate().all(|(i, block)| block.iter().all(|byte| *byte == i as u8)));
+    }
+
+    #[test]
+    fn write_errors_reach_the_caller() {
+        let root = fixtures("errors");
+        let path = root.join("device.img");
+
+        fs::write(&path, b"").unwrap();
+
+        // Read only, every write fails
+        let mut writer = Writer::new(File::open(&path).unwrap(), 8, None);
+        let mut offset = 64;
+        let failure = loop {
+            if let Err(e) = queue(&mut writer, offset, b"data") {
+                break e;
+            }
+            offset += 4;
+            assert!(offset < 64 + 4 * 4 * DEPTH as u64, "write error never reported");
+        };
+
+        let mut writer = Writer::new(File::open(&path).unwrap(), 8, None);
+        queue(&mut writer, 8, b"data").unwrap();
+        let finished = writer.finish();
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(failure, Error::Write { offset: 64, .. }));
+        assert!(matches!(finished, Err(Error::Write { offset: 8, .. })));
+    }
+}
@@ -0,0 +1,559 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Safety checks run before writing to a device.
+//!
+//! Every mounted filesystem and active swap is mapped back to the disk(s)
+//! holding it, so that writing to the system disk or to a disk that is
+//! still in use can be refused.
+
+use std::ffi::CString;
+use std::fs::{metadata,read_dir,File};
+use std::io::{self,Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt,MetadataExt};
+use std::path::{Path,PathBuf};
+
+use libc;
+
+use device::Sysfs;
+use error::{Error,Result};
+
+/// Device number, as `(major, minor)`
+pub type DevNum = (u64, u64);
+
+/// Splits a `dev_t` as encoded by glibc and the kernel.
+fn split_dev(dev: u64) -> DevNum {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+
+    (major, minor)
+}
+
+/// Mounted filesystem, as listed in `/proc/self/mountinfo`
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// Device holding the filesystem
+    pub devnum: DevNum,
+    /// Where the filesystem is mounted
+    pub mount_point: String,
+    /// Type of the filesystem
+    pub fstype: String,
+    /// Source of the mount, usually the path of its device
+    pub source: String,
+}
+
+/// Parses the content of a `mountinfo` file.
+///
+/// See proc(5) for the format. Malformed lines are ignored.
+pub fn parse_mountinfo(content: &str) -> Vec<Mount> {
+    content.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = fields.iter().position(|field| *field == "-")?;
+
+        if separator < 6 {
+            return None;
+        }
+
+        let (major, minor) = fields[2].split_once(':')?;
+        let major = major.parse().ok()?;
+        let minor = minor.parse().ok()?;
+
+        Some(Mount {
+            devnum: (major, minor),
+            mount_point: unescape(fields[4]),
+            fstype: fields.get(separator + 1)?.to_string(),
+            source: unescape(fields.get(separator + 2)?),
+        })
+    }).collect()
+}
+
+/// Parses the content of `/proc/swaps`, returning the swap paths.
+pub fn parse_swaps(content: &str) -> Vec<String> {
+    content.lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(unescape)
+        .collect()
+}
+
+/// Decodes the octal escapes (`\040` for space...) used by the kernel.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() && bytes[i+1..i+4].iter().all(|b| (b'0'..b'8').contains(b)) {
+            decoded.push((bytes[i+1] - b'0') * 64 + (bytes[i+2] - b'0') * 8 + (bytes[i+3] - b'0'));
+            i += 4;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// What currently uses a disk
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    /// Whether the disk holds the root filesystem
+    pub system: bool,
+    /// Mount points of the filesystems stored on the disk
+    pub mount_points: Vec<String>,
+    /// Swap areas stored on the disk
+    pub swaps: Vec<String>,
+}
+
+impl Usage {
+    /// Whether nothing uses the disk.
+    pub fn is_empty(&self) -> bool {
+        !self.system && self.mount_points.is_empty() && self.swaps.is_empty()
+    }
+}
+
+/// Pre-flight checker.
+///
+/// Both `/proc` and `/sys` roots can be replaced to run against fake trees.
+#[derive(Clone, Debug)]
+pub struct Preflight {
+    sysfs: Sysfs,
+    procfs: PathBuf,
+}
+
+impl Default for Preflight {
+    fn default() -> Preflight {
+        Preflight::new(Sysfs::default(), "/proc")
+    }
+}
+
+impl Preflight {
+    pub fn new<P: Into<PathBuf>>(sysfs: Sysfs, procfs: P) -> Preflight {
+        Preflight {
+            sysfs,
+            procfs: procfs.into(),
+        }
+    }
+
+    /// Names of the disks ultimately holding the block device `devnum`.
+    ///
+    /// Partitions resolve to their parent disk, and stacked devices
+    /// (device-mapper, md...) to the disks listed in their `slaves`.
+    pub fn disks_of(&self, devnum: DevNum) -> Vec<String> {
+        let link = self.sysfs.root().join(format!("dev/block/{}:{}", devnum.0, devnum.1));
+        let mut disks = Vec::new();
+
+        if let Ok(path) = link.canonicalize() {
+            self.collect_disks(&path, &mut disks);
+        }
+
+        disks
+    }
+
+    /// Names of the disks holding the filesystem mounted by `mount`.
+    ///
+    /// Filesystems on anonymous devices, such as the subvolumes of btrfs,
+    /// are found through the device their mount comes from instead.
+    pub fn disks_of_mount(&self, mount: &Mount) -> Vec<String> {
+        let mut disks = self.disks_of(mount.devnum);
+
+        if !disks.is_empty() {
+            return disks;
+        }
+
+        let name = match device_name(&mount.source) {
+            Some(name) => name,
+            None => return disks,
+        };
+
+        if mount.fstype == "btrfs" {
+            for device in self.btrfs_devices(&name) {
+                self.collect_named(&device, &mut disks);
+            }
+        }
+
+        if disks.is_empty() {
+            self.collect_named(&name, &mut disks);
+        }
+
+        disks
+    }
+
+    /// Names of all the devices of the btrfs filesystem `device` belongs to.
+    fn btrfs_devices(&self, device: &str) -> Vec<String> {
+        let filesystems = match read_dir(self.sysfs.root().join("fs/btrfs")) {
+            Ok(filesystems) => filesystems,
+            Err(_) => return Vec::new(),
+        };
+
+        for filesystem in filesystems.filter_map(|filesystem| filesystem.ok()) {
+            let devices: Vec<String> = match read_dir(filesystem.path().join("devices")) {
+                Ok(devices) => devices.filter_map(|device| device.ok())
+                    .map(|device| device.file_name().to_string_lossy().into_owned())
+                    .collect(),
+                Err(_) => continue,
+            };
+
+            if devices.iter().any(|name| name == device) {
+                return devices;
+            }
+        }
+
+        Vec::new()
+    }
+
+    fn collect_named(&self, name: &str, disks: &mut Vec<String>) {
+        if let Ok(path) = self.sysfs.root().join("class/block").join(name).canonicalize() {
+            self.collect_disks(&path, disks);
+        }
+    }
+
+    fn collect_disks(&self, path: &Path, disks: &mut Vec<String>) {
+        let path = if path.join("partition").exists() {
+            match path.parent() {
+                Some(parent) => parent,
+                None => return,
+            }
+        } else {
+            path
+        };
+
+        let mut stacked = false;
+
+        if let Ok(slaves) = read_dir(path.join("slaves")) {
+            for slave in slaves.filter_map(|slave| slave.ok()) {
+                if let Ok(slave) = slave.path().canonicalize() {
+                    stacked = true;
+                    self.collect_disks(&slave, disks);
+                }
+            }
+        }
+
+        if !stacked {
+            if let Some(name) = path.file_name() {
+                let name = name.to_string_lossy().into_owned();
+
+                if !disks.contains(&name) {
+                    disks.push(name);
+                }
+            }
+        }
+    }
+
+    fn read_proc(&self, name: &str) -> io::Result<String> {
+        let mut content = String::new();
+
+        File::open(self.procfs.join(name))?.read_to_string(&mut content)?;
+
+        Ok(content)
+    }
+
+    /// Lists what uses the disk the device at `path` belongs to.
+    ///
+    /// Regular files are never in use.
+    pub fn usage<P: AsRef<Path>>(&self, path: P) -> Result<Usage> {
+        let path = path.as_ref();
+        let meta = metadata(path).map_err(|e| Error::device_open(&path.to_string_lossy(), e))?;
+
+        if !meta.file_type().is_block_device() {
+            return Ok(Usage::default());
+        }
+
+        self.usage_of(&self.disks_of(split_dev(meta.rdev())))
+    }
+
+    /// Lists what uses any of the disks named `targets`.
+    ///
+    /// A root filesystem backed by no disk, as on overlays, tmpfs or in
+    /// containers, holds no target; the media under it are still mounted.
+    fn usage_of(&self, targets: &[String]) -> Result<Usage> {
+        let mut usage = Usage::default();
+
+        let on_target = |disks: &[String]| disks.iter().any(|disk| targets.contains(disk));
+
+        for mount in parse_mountinfo(&self.read_proc("self/mountinfo")?) {
+            let disks = self.disks_of_mount(&mount);
+
+            if on_target(&disks) {
+                if mount.mount_point == "/" {
+                    usage.system = true;
+                }
+                usage.mount_points.push(mount.mount_point);
+            }
+        }
+
+        for swap in parse_swaps(&self.read_proc("swaps")?) {
+            let devnum = match metadata(&swap) {
+                Ok(ref meta) if meta.file_type().is_block_device() => split_dev(meta.rdev()),
+                // Swap files live on a filesystem, already covered by the mounts
+                _ => continue,
+            };
+
+            if on_target(&self.disks_of(devnum)) {
+                usage.swaps.push(swap);
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Makes sure nothing uses the device at `path`.
+    ///
+    /// With `force`, filesystems are unmounted and swaps disabled instead
+    /// of refusing. The system disk is refused in any case.
+    pub fn check<P: AsRef<Path>>(&self, path: P, force: bool) -> Result<()> {
+        let path = path.as_ref();
+        let usage = self.usage(path)?;
+        let device = path.to_string_lossy().into_owned();
+
+        if usage.is_empty() {
+            return Ok(());
+        }
+
+        if usage.system {
+            return Err(Error::SystemDisk(device));
+        }
+
+        if !force {
+            let mut in_use = usage.mount_points;
+            in_use.extend(usage.swaps);
+
+            return Err(Error::DeviceInUse { device, mount_points: in_use });
+        }
+
+        for swap in &usage.swaps {
+            call(swap, |path| unsafe { libc::swapoff(path) })?;
+        }
+
+        // Nested mounts are listed after their parent, unmount them first
+        for mount_point in usage.mount_points.iter().rev() {
+            call(mount_point, |path| unsafe { libc::umount2(path, 0) })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Kernel name of the device at the absolute path `source`, following the
+/// links of `/dev/mapper` and the like.
+fn device_name(source: &str) -> Option<String> {
+    let path = Path::new(source);
+
+    if !path.is_absolute() {
+        return None;
+    }
+
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+
+    path.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Calls a libc function taking a path, turning failures into errors.
+fn call<F: Fn(*const libc::c_char) -> libc::c_int>(path: &str, function: F) -> Result<()> {
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes())
+        .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+
+    if function(c_path.as_ptr()) < 0 {
+        return Err(Error::Unmount {
+            path: path.to_owned(),
+            cause: io::Error::last_os_error(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{create_dir_all,remove_dir_all,write};
+    use std::os::unix::fs::symlink;
+    use std::path::{Path,PathBuf};
+    use std::process;
+
+    use device::Sysfs;
+
+    use super::*;
+
+    /// Empty fake `/sys` and `/proc` trees, unique to the test `name`
+    fn fake_tree(name: &str) -> (PathBuf, PathBuf) {
+        let root = env::temp_dir().join(format!("acetylene-preflight-{}-{}", name, process::id()));
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("sys/dev/block")).unwrap();
+        create_dir_all(root.join("sys/class/block")).unwrap();
+        create_dir_all(root.join("proc/self")).unwrap();
+
+        (root.join("sys"), root.join("proc"))
+    }
+
+    /// Adds the block device `name` at `path` under `devices`, as sysfs does.
+    fn add_block(sys: &Path, path: &str, devnum: &str, partition: bool) {
+        let device = sys.join("devices").join(path);
+        let name = device.file_name().unwrap().to_owned();
+
+        create_dir_all(&device).unwrap();
+        if partition {
+            write(device.join("partition"), "1\n").unwrap();
+        }
+
+        symlink(&device, sys.join("dev/block").join(devnum)).unwrap();
+        symlink(&device, sys.join("class/block").join(name)).unwrap();
+    }
+
+    /// Fake tree with two disks, the second holding the root filesystem
+    fn system_tree(name: &str) -> (PathBuf, PathBuf) {
+        let (sys, proc_) = fake_tree(name);
+
+        add_block(&sys, "pci/fakea", "8:0", false);
+        add_block(&sys, "pci/fakea/fakea1", "8:1", true);
+        add_block(&sys, "pci/fakea/fakea2", "8:2", true);
+        add_block(&sys, "pci/fakeb", "8:16", false);
+        add_block(&sys, "pci/fakeb/fakeb1", "8:17", true);
+
+        write(proc_.join("self/mountinfo"), "\
+20 1 8:17 / / rw,relatime shared:1 - ext4 /dev/fakeb1 rw
+21 20 8:1 / /media/usb\\040stick rw,relatime shared:2 - vfat /dev/fakea1 rw
+22 20 0:5 / /proc rw,nosuid - proc proc rw
+").unwrap();
+        write(proc_.join("swaps"), "Filename\tType\tSize\tUsed\tPriority\n").unwrap();
+
+        (sys, proc_)
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn mountinfo_lines_are_parsed() {
+        let mounts = parse_mountinfo("\
+36 35 98:0 /mnt1 /mnt\\0402 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+25 1 0:31 / / rw,relatime - btrfs /dev/sda2 rw,subvol=/@
+garbage
+37 35 1:2 / /x rw - ext4
+");
+
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].devnum, (98, 0));
+        assert_eq!(mounts[0].mount_point, "/mnt 2");
+        assert_eq!(mounts[0].fstype, "ext3");
+        assert_eq!(mounts[0].source, "/dev/root");
+        assert_eq!(mounts[1].devnum, (0, 31));
+        assert_eq!(mounts[1].mount_point, "/");
+        assert_eq!(mounts[1].fstype, "btrfs");
+        assert_eq!(mounts[1].source, "/dev/sda2");
+    }
+
+    #[test]
+    fn swaps_are_parsed() {
+        let swaps = parse_swaps("\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda3                               partition\t8388604\t\t0\t\t-2
+/swap\\040file                           file\t\t1048572\t\t0\t\t-3
+");
+
+        assert_eq!(swaps, strings(&["/dev/sda3", "/swap file"]));
+    }
+
+    #[test]
+    fn octal_escapes_are_decoded() {
+        assert_eq!(unescape("a\\040b\\011c"), "a b\tc");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        assert_eq!(unescape("not\\089octal"), "not\\089octal");
+        assert_eq!(unescape("short\\04"), "short\\04");
+    }
+
+    #[test]
+    fn devices_resolve_to_their_disks() {
+        let (sys, proc_) = fake_tree("disks");
+
+        add_block(&sys, "pci/fakea", "8:0", false);
+        add_block(&sys, "pci/fakea/fakea1", "8:1", true);
+        add_block(&sys, "pci/fakeb", "8:16", false);
+        add_block(&sys, "pci/fakeb/fakeb1", "8:17", true);
+        add_block(&sys, "virtual/block/dm-0", "254:0", false);
+        create_dir_all(sys.join("devices/virtual/block/dm-0/slaves")).unwrap();
+        symlink(sys.join("devices/pci/fakea/fakea1"), sys.join("devices/virtual/block/dm-0/slaves/fakea1")).unwrap();
+        symlink(sys.join("devices/pci/fakeb/fakeb1"), sys.join("devices/virtual/block/dm-0/slaves/fakeb1")).unwrap();
+
+        let preflight = Preflight::new(Sysfs::new(&sys), &proc_);
+
+        assert_eq!(preflight.disks_of((8, 0)), strings(&["fakea"]));
+        assert_eq!(preflight.disks_of((8, 1)), strings(&["fakea"]));
+        assert_eq!(preflight.disks_of((254, 0)), strings(&["fakea", "fakeb"]));
+        assert!(preflight.disks_of((0, 31)).is_empty());
+    }
+
+    #[test]
+    fn anonymous_devices_resolve_through_their_source() {
+        let (sys, proc_) = fake_tree("anonymous");
+
+        add_block(&sys, "pci/fakea", "8:0", false);
+        add_block(&sys, "pci/fakea/fakea1", "8:1", true);
+        add_block(&sys, "pci/fakeb", "8:16", false);
+        add_block(&sys, "pci/fakeb/fakeb1", "8:17", true);
+        add_block(&sys, "pci/fakec", "8:32", false);
+        create_dir_all(sys.join("fs/btrfs/features")).unwrap();
+        create_dir_all(sys.join("fs/btrfs/0d5b3e1c/devices")).unwrap();
+        symlink(sys.join("devices/pci/fakea/fakea1"), sys.join("fs/btrfs/0d5b3e1c/devices/fakea1")).unwrap();
+        symlink(sys.join("devices/pci/fakeb/fakeb1"), sys.join("fs/btrfs/0d5b3e1c/devices/fakeb1")).unwrap();
+
+        let preflight = Preflight::new(Sysfs::new(&sys), &proc_);
+        let mount = |fstype: &str, source: &str| parse_mountinfo(
+            &format!("25 1 0:31 / / rw - {} {} rw", fstype, source)
+        ).remove(0);
+
+        let mut disks = preflight.disks_of_mount(&mount("btrfs", "/dev/fakeb1"));
+        disks.sort();
+        assert_eq!(disks, strings(&["fakea", "fakeb"]));
+        assert_eq!(preflight.disks_of_mount(&mount("ext4", "/dev/fakec")), strings(&["fakec"]));
+        assert!(preflight.disks_of_mount(&mount("tmpfs", "tmpfs")).is_empty());
+    }
+
+    #[test]
+    fn usage_lists_the_mounts_of_the_target() {
+        let (sys, proc_) = system_tree("usage");
+        let preflight = Preflight::new(Sysfs::new(&sys), &proc_);
+
+        let usage = preflight.usage_of(&strings(&["fakea"])).unwrap();
+        assert!(!usage.system);
+        assert_eq!(usage.mount_points, strings(&["/media/usb stick"]));
+
+        let usage = preflight.usage_of(&strings(&["fakeb"])).unwrap();
+        assert!(usage.system);
+        assert_eq!(usage.mount_points, strings(&["/"]));
+    }
+
+    #[test]
+    fn diskless_roots_hold_no_target() {
+        let (sys, proc_) = system_tree("diskless");
+
+        write(proc_.join("self/mountinfo"), "20 1 0:40 / / rw - overlay overlay rw\n\
+                                             21 20 8:1 / /run/live/medium ro - vfat /dev/fakea1 ro\n").unwrap();
+
+        let preflight = Preflight::new(Sysfs::new(&sys), &proc_);
+
+        let usage = preflight.usage_of(&strings(&["fakeb"])).unwrap();
+        assert!(!usage.system);
+        assert!(usage.mount_points.is_empty());
+
+        let usage = preflight.usage_of(&strings(&["fakea"])).unwrap();
+        assert!(!usage.system);
+        assert_eq!(usage.mount_points, strings(&["/run/live/medium"]));
+    }
+}
@@ -0,0 +1,753 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Authentication of images by detached signatures.
+//!
+//! minisign and signify signatures are Ed25519, over the file itself or,
+//! in minisign's default "prehashed" mode, over its BLAKE2b-512 digest.
+//! minisign adds a global signature over the trusted comment. OpenPGP
+//! signatures are checked against the primary keys and signing subkeys
+//! of the keyrings.
+
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,BufReader,Cursor,Read,Seek,SeekFrom};
+use std::str;
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use blake2::{Blake2b512,Digest};
+use ed25519_dalek::{Signature as EdSignature,VerifyingKey};
+use pgp::{Deserializable,Signature,SignedPublicKey,SignedPublicSubKey,StandaloneSignature};
+use pgp::packet::SignatureType;
+use pgp::types::PublicKeyTrait;
+
+use checksum::{Checksum,HashAlgorithm,Hasher,hex};
+use error::{Error,Result};
+use source;
+
+const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
+
+const UNTRUSTED_COMMENT: &str = "untrusted comment:";
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+const PGP_ARMOR: &[u8] = b"-----BEGIN PGP";
+
+/// Signature algorithm IDs of minisign, signing the data or its BLAKE2b-512 digest
+const ED25519: &[u8] = b"Ed";
+const ED25519_PREHASHED: &[u8] = b"ED";
+
+/// Detached signature an image must carry
+#[derive(Clone, Debug)]
+pub struct SignatureCheck {
+    /// minisign, signify or OpenPGP (armored or binary) signature file
+    pub signature: String,
+    /// Files of the trusted keys: minisign or signify public keys, OpenPGP
+    /// keyrings (armored or binary)
+    pub keys: Vec<String>,
+    /// File the signature covers, if not the image itself: a checksum file
+    /// listing the image, such as SHA256SUMS
+    pub signed_file: Option<String>,
+}
+
+/// Format of a signature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureKind {
+    Minisign,
+    Signify,
+    OpenPgp,
+}
+
+impl fmt::Display for SignatureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SignatureKind::Minisign => write!(f, "minisign"),
+            SignatureKind::Signify => write!(f, "signify"),
+            SignatureKind::OpenPgp => write!(f, "OpenPGP"),
+        }
+    }
+}
+
+fn rejected(reason: String) -> Error {
+    Error::BadSignature(reason)
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| rejected(format!("can't read {}: {}", path, e)))
+}
+
+/// Feeds the content of `signed`, from its start, to `input`.
+fn stream<R: Read + Seek, F: FnMut(&[u8])>(signed: &mut R, mut input: F) -> Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    signed.seek(SeekFrom::Start(0))?;
+
+    loop {
+        match source::read_full(signed, &mut buffer)? {
+            0 => return Ok(()),
+            n => input(&buffer[..n]),
+        }
+    }
+}
+
+/// Checks `signature` over `signed`, named `name`.
+fn verify_signed<R: Read + Seek>(signature: &[u8], keys: &[String], signed: &mut R, name: &str)
+    -> Result<SignatureKind>
+{
+    if signature.starts_with(UNTRUSTED_COMMENT.as_bytes()) {
+        verify_minisign(signature, keys, signed, name)
+    } else {
+        verify_openpgp(signature, keys, signed, name)?;
+        Ok(SignatureKind::OpenPgp)
+    }
+}
+
+impl SignatureCheck {
+    /// Checks the signature of `image`, or of the checksum file covering it.
+    ///
+    /// See `verify_file`.
+    pub fn verify(&self, image: &str) -> Result<SignatureKind> {
+        let mut file = File::open(image).map_err(|e| Error::image_open(image, e))?;
+
+        self.verify_file(&mut file, image)
+    }
+
+    /// Checks the signature of the opened image `file`, named `image`, or of
+    /// the checksum file covering it.
+    ///
+    /// In the latter case, the image is then hashed and checked against the
+    /// checksum file, so that it is authenticated either way.
+    ///
+    /// Burning from `file` rules out a different file being put at the
+    /// image's path, not the image being changed in place after the check:
+    /// it is read again to be burnt, and must only be writable by trusted
+    /// users.
+    pub fn verify_file(&self, file: &mut File, image: &str) -> Result<SignatureKind> {
+        self.check(file, image)
+    }
+
+    /// As `verify_file`, also hashing the image with `algorithm` as it is
+    /// read for the check. Returns its digest along with the kind of the
+    /// signature.
+    pub fn verify_hashing(&self, file: &mut File, image: &str, algorithm: HashAlgorithm)
+        -> Result<(SignatureKind, Vec<u8>)>
+    {
+        let mut tap = Tap { inner: file, hasher: Hasher::new(algorithm), position: 0, hashed: 0 };
+
+        tap.seek(SeekFrom::Start(0))?;
+
+        let kind = self.check(&mut tap, image)?;
+
+        // Whatever the check didn't read, if anything
+        let hashed = tap.hashed;
+        tap.seek(SeekFrom::Start(hashed))?;
+        io::copy(&mut tap, &mut io::sink())?;
+
+        Ok((kind, tap.hasher.result()))
+    }
+
+    fn check<R: Read + Seek>(&self, file: &mut R, image: &str) -> Result<SignatureKind> {
+        let signature = read(&self.signature)?;
+
+        let path = match self.signed_file {
+            Some(ref path) => path,
+            None => return verify_signed(&signature, &self.keys, file, image),
+        };
+
+        let sums = read(path)?;
+        let kind = verify_signed(&signature, &self.keys, &mut Cursor::new(&sums), path)?;
+
+        let expected = Checksum::find(&String::from_utf8_lossy(&sums), image)?;
+        let mut hasher = Hasher::new(expected.algorithm);
+
+        stream(file, |data| hasher.input(data))?;
+
+        let digest = hasher.result();
+
+        if digest != expected.digest {
+            return Err(Error::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: Checksum { algorithm: expected.algorithm, digest }.to_string(),
+                device: None,
+            });
+        }
+
+        Ok(kind)
+    }
+}
+
+/// Hashes what is read through it, as long as it extends what was read from
+/// the start, so that reading again doesn't hash twice.
+struct Tap<R> {
+    inner: R,
+    hasher: Hasher,
+    position: u64,
+    /// Number of bytes hashed, from the start
+    hashed: u64,
+}
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let end = self.position + n as u64;
+
+        if self.position <= self.hashed && self.hashed < end {
+            self.hasher.input(&buf[(self.hashed - self.position) as usize..n]);
+            self.hashed = end;
+        }
+
+        self.position = end;
+
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for Tap<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(position)?;
+
+        Ok(self.position)
+    }
+}
+
+/// Ed25519 public key of minisign or signify
+struct EdKey {
+    id: [u8; 8],
+    key: VerifyingKey,
+}
+
+impl EdKey {
+    /// Parses a public key file, or a bare base64 key: "Ed", the key ID,
+    /// then the key itself.
+    fn parse(content: &[u8]) -> Option<EdKey> {
+        let line = str::from_utf8(content).ok()?.lines().map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_COMMENT))?;
+        let bytes = BASE64.decode(line).ok()?;
+
+        if bytes.len() != 42 || &bytes[..2] != ED25519 {
+            return None;
+        }
+
+        let mut id = [0u8; 8];
+        let mut key = [0u8; 32];
+
+        id.copy_from_slice(&bytes[2..10]);
+        key.copy_from_slice(&bytes[10..42]);
+
+        Some(EdKey { id, key: VerifyingKey::from_bytes(&key).ok()? })
+    }
+}
+
+/// Key IDs are shown as minisign does, as a little endian number.
+fn key_id(id: &[u8]) -> String {
+    id.iter().rev().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Checks a minisign signature or, without a trusted comment, a signify one.
+fn verify_minisign<R: Read + Seek>(signature: &[u8], keys: &[String], signed: &mut R, name: &str)
+    -> Result<SignatureKind>
+{
+    let malformed = || rejected("malformed minisign/signify signature".to_owned());
+
+    let text = str::from_utf8(signature).map_err(|_| malformed())?;
+    let mut lines = text.lines().map(|line| line.trim_end_matches('\r')).skip(1);
+
+    let sig = lines.next().and_then(|line| BASE64.decode(line.trim()).ok())
+        .filter(|sig| sig.len() == 74)
+        .ok_or_else(malformed)?;
+
+    let (kind, global) = match lines.next() {
+        Some(line) if line.starts_with(TRUSTED_COMMENT) => {
+            let global = lines.next().and_then(|line| BASE64.decode(line.trim()).ok())
+                .and_then(|global| EdSignature::from_slice(&global).ok())
+                .ok_or_else(malformed)?;
+
+            (SignatureKind::Minisign, Some((&line[TRUSTED_COMMENT.len()..], global)))
+        }
+        _ => (SignatureKind::Signify, None),
+    };
+
+    let algorithm = &sig[..2];
+    let id = &sig[2..10];
+    let ed_signature = EdSignature::from_slice(&sig[10..]).map_err(|_| malformed())?;
+
+    let mut key = None;
+
+    for path in keys {
+        if let Some(parsed) = EdKey::parse(&read(path)?) {
+            if parsed.id == id {
+                key = Some(parsed.key);
+                break;
+            }
+        }
+    }
+
+    let key = key.ok_or_else(|| rejected(format!("no trusted key with ID {}", key_id(id))))?;
+    let invalid = |_| rejected(format!("{} signature doesn't match {}", kind, name));
+
+    if algorithm == ED25519_PREHASHED && kind == SignatureKind::Minisign {
+        let mut hasher = Blake2b512::new();
+
+        stream(signed, |data| hasher.update(data))?;
+        key.verify_strict(&hasher.finalize(), &ed_signature).map_err(invalid)?;
+    } else if algorithm == ED25519 {
+        let mut verifier = key.verify_stream(&ed_signature).map_err(invalid)?;
+
+        stream(signed, |data| verifier.update(data))?;
+        verifier.finalize_and_verify().map_err(invalid)?;
+    } else {
+        return Err(rejected(format!("unsupported signature algorithm {}", hex(algorithm))));
+    }
+
+    // The trusted comment is signed along with the signature
+    if let Some((comment, global)) = global {
+        let mut message = sig[10..].to_vec();
+        message.extend_from_slice(comment.as_bytes());
+
+        key.verify_strict(&message, &global)
+            .map_err(|_| rejected("minisign trusted comment was tampered with".to_owned()))?;
+    }
+
+    Ok(kind)
+}
+
+/// Reads every key of the OpenPGP keyrings at `paths`.
+fn openpgp_keys(paths: &[String]) -> Result<Vec<SignedPublicKey>> {
+    let mut keys = vec![];
+
+    for path in paths {
+        let content = read(path)?;
+        let malformed = |e: pgp::errors::Error| rejected(format!("malformed OpenPGP keyring {}: {}", path, e));
+
+        let parsed = if content.starts_with(PGP_ARMOR) {
+            SignedPublicKey::from_armor_many(&content[..]).map_err(malformed)?.0
+        } else {
+            SignedPublicKey::from_bytes_many(&content[..])
+        };
+
+        for key in parsed {
+            keys.push(key.map_err(malformed)?);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Whether a key created at `created` and valid for `validity` seconds is
+/// expired at `now`. A validity of 0 never expires.
+fn expired(created: i64, validity: Option<i64>, now: i64) -> bool {
+    validity.is_some_and(|validity| validity > 0 && created + validity <= now)
+}
+
+/// Why the primary key of `key` can't sign anymore, if it can't.
+///
+/// `SignedPublicKey::verify` only checks that revocations are genuine, not
+/// that there are none.
+fn primary_key_problem(key: &SignedPublicKey, now: i64) -> Option<&'static str> {
+    if key.details.revocation_signatures.iter().any(|sig| sig.typ() == SignatureType::KeyRevocation) {
+        return Some("is revoked");
+    }
+
+    // Version 3 keys carry their validity, in days
+    let validity = key.details.key_expiration_time().map(|validity| validity.num_seconds())
+        .or_else(|| key.primary_key.expiration().map(|days| days as i64 * 24 * 3600));
+
+    if expired(key.primary_key.created_at().timestamp(), validity, now) {
+        return Some("is expired");
+    }
+
+    None
+}
+
+/// Why `subkey` can't sign, if it can't, going by its latest binding.
+fn subkey_problem(subkey: &SignedPublicSubKey, now: i64) -> Option<&'static str> {
+    if subkey.signatures.iter().any(|sig| sig.typ() == SignatureType::SubkeyRevocation) {
+        return Some("is revoked");
+    }
+
+    let binding = subkey.signatures.iter()
+        .filter(|sig| sig.typ() == SignatureType::SubkeyBinding)
+        .max_by_key(|sig| sig.created().map(|created| created.timestamp()));
+
+    let binding = match binding {
+        Some(binding) => binding,
+        None => return Some("isn't bound to its primary key"),
+    };
+
+    if !binding.key_flags().sign() {
+        return Some("isn't a signing key");
+    }
+
+    let validity = binding.key_expiration_time().map(|validity| validity.num_seconds());
+
+    if expired(subkey.key.created_at().timestamp(), validity, now) {
+        return Some("is expired");
+    }
+
+    None
+}
+
+/// Checks `signature` over `signed` with `key`, if the key is one of its
+/// issuers. Returns `None` when it isn't, or when the key can't sign
+/// because of `problem`, which is then recorded in `refused`.
+fn try_openpgp_key<K: PublicKeyTrait, R: Read + Seek>(signature: &Signature, key: &K, problem: Option<&str>,
+                                                      refused: &mut Option<String>, signed: &mut R)
+    -> Result<Option<bool>>
+{
+    let issuers = signature.issuer();
+
+    if !issuers.is_empty() && !issuers.contains(&&key.key_id()) {
+        return Ok(None);
+    }
+
+    if let Some(problem) = problem {
+        *refused = Some(format!("OpenPGP key {:X} {}", key.key_id(), problem));
+        return Ok(None);
+    }
+
+    signed.seek(SeekFrom::Start(0))?;
+
+    Ok(Some(signature.verify(key, BufReader::with_capacity(BUFFER_SIZE, &mut *signed)).is_ok()))
+}
+
+/// Checks an OpenPGP signature, made by a key or subkey of the keyrings.
+///
+/// Keys whose self-signatures don't check out, and subkeys not bound to
+/// their primary key, are ignored. Revoked or expired keys, and subkeys
+/// not meant for signing, are refused.
+fn verify_openpgp<R: Read + Seek>(signature: &[u8], keys: &[String], signed: &mut R, name: &str) -> Result<()> {
+    let signature = if signature.starts_with(PGP_ARMOR) {
+        StandaloneSignature::from_armor_single(signature).map(|(signature, _)| signature)
+    } else {
+        StandaloneSignature::from_bytes(signature)
+    };
+    let signature = signature.map_err(|e| rejected(format!("malformed OpenPGP signature: {}", e)))?.signature;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64);
+    let mut tried = false;
+    let mut refused = None;
+
+    for key in openpgp_keys(keys)? {
+        if key.verify().is_err() {
+            continue;
+        }
+
+        let problem = primary_key_problem(&key, now);
+        let mut results = vec![try_openpgp_key(&signature, &key.primary_key, problem, &mut refused, signed)?];
+
+        for subkey in &key.public_subkeys {
+            if subkey.verify(&key.primary_key).is_ok() {
+                // A subkey can't outlive its primary key
+                let problem = problem.or_else(|| subkey_problem(subkey, now));
+
+                results.push(try_openpgp_key(&signature, &subkey.key, problem, &mut refused, signed)?);
+            }
+        }
+
+        if results.contains(&Some(true)) {
+            return Ok(());
+        }
+
+        tried |= results.iter().any(Option::is_some);
+    }
+
+    match refused {
+        _ if tried => Err(rejected(format!("OpenPGP signature doesn't match {}", name))),
+        Some(reason) => Err(rejected(reason)),
+        None => Err(rejected("OpenPGP signature made by no trusted key".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{create_dir_all,remove_dir_all,write};
+    use std::path::{Path,PathBuf};
+    use std::process;
+
+    use ed25519_dalek::{Signer,SigningKey};
+    use sha2::Sha256;
+
+    const IMAGE: &[u8] = b"image to burn";
+    const KEY_ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    /// Empty directory of fixtures, unique to the test `name`
+    fn fixtures(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("acetylene-signature-{}-{}", name, process::id()));
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn fixture(root: &Path, name: &str, content: &[u8]) -> String {
+        let path = root.join(name);
+
+        write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// minisign public key file of `key`
+    fn public_key(key: &SigningKey, id: [u8; 8]) -> Vec<u8> {
+        let mut bytes = ED25519.to_vec();
+
+        bytes.extend_from_slice(&id);
+        bytes.extend_from_slice(key.verifying_key().as_bytes());
+
+        format!("untrusted comment: minisign public key\n{}\n", BASE64.encode(bytes)).into_bytes()
+    }
+
+    /// Signature line of `message`, made with `algorithm`
+    fn signature_line(key: &SigningKey, algorithm: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut sig = algorithm.to_vec();
+
+        sig.extend_from_slice(&KEY_ID);
+        sig.extend_from_slice(&key.sign(message).to_bytes());
+        sig
+    }
+
+    /// minisign signature of `data`, prehashed or not, with `comment` as trusted comment
+    fn minisign(key: &SigningKey, data: &[u8], prehashed: bool, comment: &str) -> Vec<u8> {
+        let sig = if prehashed {
+            signature_line(key, ED25519_PREHASHED, &Blake2b512::digest(data))
+        } else {
+            signature_line(key, ED25519, data)
+        };
+
+        let mut global = sig[10..].to_vec();
+        global.extend_from_slice(comment.as_bytes());
+
+        format!(
+            "untrusted comment: signature\n{}\ntrusted comment: {}\n{}\n",
+            BASE64.encode(&sig), comment, BASE64.encode(key.sign(&global).to_bytes())
+        ).into_bytes()
+    }
+
+    /// signify signature of `data`
+    fn signify(key: &SigningKey, data: &[u8]) -> Vec<u8> {
+        format!("untrusted comment: signify signature\n{}\n", BASE64.encode(signature_line(key, ED25519, data)))
+            .into_bytes()
+    }
+
+    /// Checks the image `content`, signed by `signature`, trusting the key of seed 1.
+    fn check(name: &str, content: &[u8], signature: &[u8]) -> Result<SignatureKind> {
+        let root = fixtures(name);
+        let image = fixture(&root, "image.img", content);
+        let check = SignatureCheck {
+            signature: fixture(&root, "image.img.sig", signature),
+            keys: vec![fixture(&root, "key.pub", &public_key(&signing_key(1), KEY_ID))],
+            signed_file: None,
+        };
+
+        let result = check.verify(&image);
+
+        let _ = remove_dir_all(&root);
+        result
+    }
+
+    fn rejection(result: Result<SignatureKind>) -> String {
+        match result {
+            Err(Error::BadSignature(reason)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(kind) => panic!("accepted as {}", kind),
+        }
+    }
+
+    #[test]
+    fn minisign_prehashed_signatures_are_checked() {
+        let signature = minisign(&signing_key(1), IMAGE, true, "timestamp:1");
+
+        assert_eq!(check("prehashed", IMAGE, &signature).unwrap(), SignatureKind::Minisign);
+        assert!(rejection(check("prehashed-tampered", b"image to bUrn", &signature)).contains("doesn't match"));
+    }
+
+    #[test]
+    fn minisign_legacy_signatures_are_checked() {
+        let signature = minisign(&signing_key(1), IMAGE, false, "timestamp:1");
+
+        assert_eq!(check("legacy", IMAGE, &signature).unwrap(), SignatureKind::Minisign);
+        assert!(rejection(check("legacy-tampered", &IMAGE[1..], &signature)).contains("doesn't match"));
+    }
+
+    #[test]
+    fn tampered_trusted_comments_are_rejected() {
+        let signature = String::from_utf8(minisign(&signing_key(1), IMAGE, true, "timestamp:1")).unwrap();
+        let tampered = signature.replace("timestamp:1", "timestamp:2");
+
+        assert!(rejection(check("comment", IMAGE, tampered.as_bytes())).contains("trusted comment"));
+    }
+
+    #[test]
+    fn signify_signatures_are_checked() {
+        let signature = signify(&signing_key(1), IMAGE);
+
+        assert_eq!(check("signify", IMAGE, &signature).unwrap(), SignatureKind::Signify);
+        assert!(rejection(check("signify-tampered", b"other image", &signature)).contains("doesn't match"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let root = fixtures("unknown");
+        let image = fixture(&root, "image.img", IMAGE);
+        let check = SignatureCheck {
+            signature: fixture(&root, "image.img.minisig", &minisign(&signing_key(1), IMAGE, true, "t")),
+            keys: vec![fixture(&root, "other.pub", &public_key(&signing_key(1), [9; 8]))],
+            signed_file: None,
+        };
+
+        let reason = rejection(check.verify(&image));
+
+        let _ = remove_dir_all(&root);
+        assert_eq!(reason, format!("no trusted key with ID {}", key_id(&KEY_ID)));
+    }
+
+    #[test]
+    fn signatures_by_another_key_with_the_same_id_are_rejected() {
+        let signature = minisign(&signing_key(2), IMAGE, true, "timestamp:1");
+
+        assert!(rejection(check("impostor", IMAGE, &signature)).contains("doesn't match"));
+    }
+
+    #[test]
+    fn images_are_checked_against_signed_checksum_files() {
+        let root = fixtures("sums");
+        let image = fixture(&root, "image.img", IMAGE);
+        let digest = hex(&Sha256::digest(IMAGE));
+        let sums = format!("{}  other.img\n{}  image.img\n", "0".repeat(64), digest);
+        let signed_sums = fixture(&root, "SHA256SUMS", sums.as_bytes());
+        let check = SignatureCheck {
+            signature: fixture(&root, "SHA256SUMS.minisig", &minisign(&signing_key(1), sums.as_bytes(), true, "t")),
+            keys: vec![fixture(&root, "key.pub", &public_key(&signing_key(1), KEY_ID))],
+            signed_file: Some(signed_sums.clone()),
+        };
+
+        assert_eq!(check.verify(&image).unwrap(), SignatureKind::Minisign);
+
+        // The image doesn't match its signed checksum
+        let tampered = fixture(&root, "image.img", b"image to bUrn");
+        assert!(matches!(check.verify(&tampered), Err(Error::ChecksumMismatch { .. })));
+
+        // The checksum file doesn't match its signature
+        fixture(&root, "image.img", IMAGE);
+        fixture(&root, "SHA256SUMS", sums.replace("  other", "  evil").as_bytes());
+        let reason = rejection(check.verify(&image));
+
+        let _ = remove_dir_all(&root);
+        assert!(reason.contains("doesn't match"));
+        assert!(reason.contains(&signed_sums));
+    }
+
+    /// OpenPGP keys and signatures of "data\n" made with GnuPG: a valid key, a
+    /// revoked one, an expired one, and a subkey re-flagged for
+    /// authentication only after signing.
+    const GOOD_KEY: &str = concat!(
+        "mDMEatNpghYJKwYBBAHaRw8BAQdAsaqHjW1RsJF7OAzKuenrAHL9VOxEqpiiS6ecLTW4A4q0Bmdvb2RAeIiQBBMWCAA4FiEE",
+        "6uhUZR0P0kcVToed8Hy+5Z4bcj4FAmrTaYICGwMFCwkIBwIGFQoJCAsCBBYCAwECHgECF4AACgkQ8Hy+5Z4bcj6/jwEA6fY6",
+        "fsBnTu0OsqhgRsiBJ+4yrrRBYTzAoCGIQrQvidABAJGKWSSwEkx+qWzEUnvNEfRk4sniLAhfwv8eUhm7Ep0E",
+    );
+    const GOOD_SIG: &str = concat!(
+        "iH0EABYIACUWIQTq6FRlHQ/SRxVOh53wfL7lnhtyPgUCatNpggccZ29vZEB4AAoJEPB8vuWeG3I+MOQBALEfPc/K++7hZH4f",
+        "JSIGe64nEkm0+KF4zaqFFYdUsfq5APwLYA6fMnQUjIGkCZ03/90w1G3gowfdkmpsP4c4hpkXDA==",
+    );
+    const REVOKED_KEY: &str = concat!(
+        "mDMEatNpghYJKwYBBAHaRw8BAQdA0Gg4gzBqh9pi1kRC4YhB7etDHBMucrCsCmxmdh0z14SIeAQgFggAIBYhBBfH2WbHfDrx",
+        "TSlNWDx116D+9U1ABQJq02mCAh0AAAoJEDx116D+9U1AvNYA/jdPiRGLCz/q5bxlqP5b5UW/elKEOvngxB6X1msBoDRPAQC/",
+        "yyNUQ6g55i1XSVZPFm+SKpfYoZZvlMvlFyMciOwlALQFcmV2QHiIkAQTFggAOBYhBBfH2WbHfDrxTSlNWDx116D+9U1ABQJq",
+        "02mCAhsDBQsJCAcCBhUKCQgLAgQWAgMBAh4BAheAAAoJEDx116D+9U1AvMIA/RZPtf9G5mQXDW/CILO0ys0D93CGBWEcwnfs",
+        "6L5oHrqhAP9H6hyFLHQnu5zwVP6AWoFglwupfujsA+QSZUxeAc7tDQ==",
+    );
+    const REVOKED_SIG: &str = concat!(
+        "iHwEABYIACQWIQQXx9lmx3w68U0pTVg8ddeg/vVNQAUCatNpggYccmV2QHgACgkQPHXXoP71TUC4pwD+M09EWoePD+yb5p8M",
+        "FUBZObuolnLnAQYxQX+zsd2YXZYBANbEjEPVUr5r5AceCztWXQlAkIZYE4G/LbBfOh9rmacA",
+    );
+    const EXPIRED_KEY: &str = concat!(
+        "mDMEatNpghYJKwYBBAHaRw8BAQdASQirw7CvHSPCCW+eN04yEt34Jd7ibsc/nXDd+ymBHCa0BWV4cEB4iJYEExYIAD4CGwMF",
+        "CwkIBwIGFQoJCAsCBBYCAwECHgECF4AWIQRufQT7hdZvcJC89ng+Hs0Bt3Du/AUCatNpgwUJAAAAAQAKCRA+Hs0Bt3Du/JP/",
+        "AP9O8Om7Iupaubehb/eaLx2eMXduyR3mAnSxCsYo5HVczwD/duhK4x08VxsQrhSPnfw1e8UB4Z2f5rb7h6NSf6H6Bw4=",
+    );
+    const EXPIRED_SIG: &str = concat!(
+        "iHwEABYIACQWIQRufQT7hdZvcJC89ng+Hs0Bt3Du/AUCatNpggYcZXhwQHgACgkQPh7NAbdw7vxLmQEA0xWWLFEW5RMXXufR",
+        "JhLN30v14KZR5gqamk/JKRZTMuwA/AjQwRE/XPDASAgqhi8TeN/a2T/tFxf6eEqCS/H+5gkP",
+    );
+    const AUTH_KEY: &str = concat!(
+        "mDMEatNpjBYJKwYBBAHaRw8BAQdAaCSxTIgBnT5nN8YeJHzLheQcLWw+JoP6blzzUNUvH260BmF1dGhAeIiQBBMWCAA4FiEE",
+        "Nd1RtknXAdEaUgSDDRuaI/qdPrsFAmrTaYwCGwEFCwkIBwIGFQoJCAsCBBYCAwECHgECF4AACgkQDRuaI/qdPrsV7AEAisQK",
+        "wBTX48EivD7VTOyY6YdHS/b1/o6o/HpnzmXUTtgA/3Cu5omz3ax7CywnUUBR6/076CZynXCK8mDnMkkvQw0IuDMEatNpjBYJ",
+        "KwYBBAHaRw8BAQdAgCwlfoYzxRbqf971wv8POsEPkWY3K19tNZGj+p2QMqeI7wQYFggAIBYhBDXdUbZJ1wHRGlIEgw0bmiP6",
+        "nT67BQJq02mNAhsgAIF2IAQZFggAHRYhBJQNrv5BaXH1kyla8rfwr4t5pZKfBQJq02mMAAoJELfwr4t5pZKfjigBAJcAtoxB",
+        "jzL9z02ea+M4Mo1HNVOEouQS3GsbqB17eQpGAQCGAVwVqzxbA6yhEcJ8SKKoe7OHXNVCk6jeSZ6vYbj6AgkQDRuaI/qdPruy",
+        "nAEAzvMnNcasv/HYSLyQe8v06BmEoIjOPgYyWAvB0sPQYFMA/RBXKVjShwzkK3SNrNZTh4iIQ8T6jX3etJAYzfBNdksE",
+    );
+    const AUTH_SIG: &str = concat!(
+        "iHUEABYIAB0WIQSUDa7+QWlx9ZMpWvK38K+LeaWSnwUCatNpjAAKCRC38K+LeaWSnxcqAP9haKEIBGcaO9z+I9BTeXzSglZQ",
+        "ZNU+px5DjYgDzB7GUQD+P7/uga/Yd+t2MKXf+PQhnQJRCx2ViR0rIdqgnIy3gQQ=",
+    );
+
+    fn check_openpgp(name: &str, key: &str, signature: &str) -> Result<SignatureKind> {
+        let root = fixtures(name);
+        let image = fixture(&root, "image.img", b"data\n");
+        let check = SignatureCheck {
+            signature: fixture(&root, "image.img.sig", &BASE64.decode(signature).unwrap()),
+            keys: vec![fixture(&root, "key.pgp", &BASE64.decode(key).unwrap())],
+            signed_file: None,
+        };
+
+        let result = check.verify(&image);
+
+        let _ = remove_dir_all(&root);
+        result
+    }
+
+    #[test]
+    fn openpgp_keys_must_be_able_to_sign() {
+        assert_eq!(check_openpgp("pgp-good", GOOD_KEY, GOOD_SIG).unwrap(), SignatureKind::OpenPgp);
+        assert!(rejection(check_openpgp("pgp-revoked", REVOKED_KEY, REVOKED_SIG)).ends_with("is revoked"));
+        assert!(rejection(check_openpgp("pgp-expired", EXPIRED_KEY, EXPIRED_SIG)).ends_with("is expired"));
+        assert!(rejection(check_openpgp("pgp-auth", AUTH_KEY, AUTH_SIG)).ends_with("isn't a signing key"));
+        assert_eq!(rejection(check_openpgp("pgp-other", GOOD_KEY, REVOKED_SIG)), "OpenPGP signature made by no trusted key");
+    }
+
+    #[test]
+    fn images_are_hashed_once_while_checked() {
+        let root = fixtures("hashing");
+        let image = fixture(&root, "image.img", b"data\n");
+        let minisign = SignatureCheck {
+            signature: fixture(&root, "image.img.minisig", &minisign(&signing_key(1), b"data\n", false, "t")),
+            keys: vec![fixture(&root, "key.pub", &public_key(&signing_key(1), KEY_ID))],
+            signed_file: None,
+        };
+        let openpgp = SignatureCheck {
+            signature: fixture(&root, "image.img.sig", &BASE64.decode(GOOD_SIG).unwrap()),
+            keys: vec![fixture(&root, "key.pgp", &BASE64.decode(GOOD_KEY).unwrap())],
+            signed_file: None,
+        };
+        let expected = Sha256::digest(b"data\n").to_vec();
+
+        for check in &[minisign, openpgp] {
+            let mut file = File::open(&image).unwrap();
+            let (_, digest) = check.verify_hashing(&mut file, &image, HashAlgorithm::Sha256).unwrap();
+
+            assert_eq!(digest, expected);
+        }
+
+        let _ = remove_dir_all(&root);
+    }
+}
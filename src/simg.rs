@@ -0,0 +1,352 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Android sparse images, as produced by AOSP's img2simg.
+//!
+//! The image is a header followed by chunks, each one describing a run of
+//! blocks: raw data, a repeated 32 bits value, or blocks to leave untouched.
+//! CRC32 chunks carry the checksum of everything expanded before them.
+
+use std::io::{self,Read};
+
+use error::{Error,Result};
+
+/// First bytes of a sparse image (0xED26FF3A, little endian).
+pub const MAGIC: &[u8] = &[0x3a, 0xff, 0x26, 0xed];
+
+const HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+
+/// Whether `header` starts a sparse image.
+pub fn is_sparse(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    le16(bytes) as u32 | (le16(&bytes[2..]) as u32) << 16
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidSparse(reason.to_owned())
+}
+
+/// Reads exactly `buf.len()` bytes, a truncated image being invalid.
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("truncated image"),
+        _ => Error::Io(e),
+    })
+}
+
+/// Skips `count` bytes, used to jump over header extensions.
+fn skip<R: Read>(reader: &mut R, count: usize) -> Result<()> {
+    let copied = io::copy(&mut reader.take(count as u64), &mut io::sink())?;
+
+    if copied < count as u64 {
+        return Err(invalid("truncated image"));
+    }
+
+    Ok(())
+}
+
+/// Run of blocks, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunk {
+    /// Data follows in the image
+    Raw(u64),
+    /// The 32 bits value, repeated
+    Fill(u32, u64),
+    /// Blocks to leave untouched
+    DontCare(u64),
+    /// CRC32 of the expanded data so far
+    Crc32(u32),
+}
+
+/// Reads the chunk headers of a sparse image.
+///
+/// The data of `Raw` chunks must be consumed by the caller before asking
+/// for the next chunk.
+pub struct SparseReader {
+    /// Size of the blocks, in bytes
+    pub block_size: u32,
+    /// Number of blocks of the expanded image
+    pub blocks: u32,
+    /// CRC32 of the expanded image, 0 when not computed
+    pub checksum: u32,
+    chunks: u32,
+    chunk_header_size: usize,
+    read: u32,
+}
+
+impl SparseReader {
+    /// Reads the sparse image header.
+    pub fn new<R: Read>(reader: &mut R) -> Result<SparseReader> {
+        let mut header = [0u8; HEADER_SIZE];
+
+        read_exact(reader, &mut header)?;
+
+        if !is_sparse(&header) {
+            return Err(invalid("bad magic"));
+        }
+
+        if le16(&header[4..]) != 1 {
+            return Err(invalid("unsupported major version"));
+        }
+
+        let header_size = le16(&header[8..]) as usize;
+        let chunk_header_size = le16(&header[10..]) as usize;
+        let block_size = le32(&header[12..]);
+
+        if header_size < HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+            return Err(invalid("bad header size"));
+        }
+
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid("bad block size"));
+        }
+
+        skip(reader, header_size - HEADER_SIZE)?;
+
+        Ok(SparseReader {
+            block_size,
+            blocks: le32(&header[16..]),
+            chunks: le32(&header[20..]),
+            checksum: le32(&header[24..]),
+            chunk_header_size,
+            read: 0,
+        })
+    }
+
+    /// Size of the expanded image, in bytes.
+    pub fn size(&self) -> u64 {
+        self.block_size as u64 * self.blocks as u64
+    }
+
+    /// Reads the next chunk header, with its payload for `Fill` and `Crc32`.
+    pub fn next_chunk<R: Read>(&mut self, reader: &mut R) -> Result<Option<Chunk>> {
+        if self.read == self.chunks {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; CHUNK_HEADER_SIZE];
+
+        read_exact(reader, &mut header)?;
+        skip(reader, self.chunk_header_size - CHUNK_HEADER_SIZE)?;
+
+        self.read += 1;
+
+        let kind = le16(&header);
+        let length = le32(&header[4..]) as u64 * self.block_size as u64;
+        let payload = (le32(&header[8..]) as u64).checked_sub(self.chunk_header_size as u64)
+            .ok_or_else(|| invalid("bad chunk size"))?;
+
+        let expected = match kind {
+            CHUNK_RAW => length,
+            CHUNK_FILL | CHUNK_CRC32 => 4,
+            CHUNK_DONT_CARE => 0,
+            _ => return Err(invalid("unknown chunk type")),
+        };
+
+        if payload != expected {
+            return Err(invalid("bad chunk size"));
+        }
+
+        let mut value = [0u8; 4];
+
+        Ok(Some(match kind {
+            CHUNK_RAW => Chunk::Raw(length),
+            CHUNK_FILL => {
+                read_exact(reader, &mut value)?;
+                Chunk::Fill(le32(&value), length)
+            }
+            CHUNK_DONT_CARE => Chunk::DontCare(length),
+            _ => {
+                read_exact(reader, &mut value)?;
+                Chunk::Crc32(le32(&value))
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn header(chunks: u32, header_size: u16, chunk_header_size: u16) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+
+        header.extend_from_slice(&[1, 0, 0, 0]);
+        header.extend_from_slice(&header_size.to_le_bytes());
+        header.extend_from_slice(&chunk_header_size.to_le_bytes());
+        header.extend_from_slice(&BLOCK_SIZE.to_le_bytes());
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&chunks.to_le_bytes());
+        header.extend_from_slice(&0x1234_5678u32.to_le_bytes());
+        header.resize(header_size as usize, 0xee);
+        header
+    }
+
+    fn chunk(image: &mut Vec<u8>, kind: u16, blocks: u32, total_size: u32) {
+        image.extend_from_slice(&kind.to_le_bytes());
+        image.extend_from_slice(&[0, 0]);
+        image.extend_from_slice(&blocks.to_le_bytes());
+        image.extend_from_slice(&total_size.to_le_bytes());
+    }
+
+    /// Reason of the `InvalidSparse` error the first chunk of `image` fails with.
+    fn failure(image: Vec<u8>) -> String {
+        let mut image = Cursor::new(image);
+        let mut reader = SparseReader::new(&mut image).unwrap();
+
+        match reader.next_chunk(&mut image) {
+            Err(Error::InvalidSparse(reason)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(chunk) => panic!("unexpected chunk {:?}", chunk),
+        }
+    }
+
+    #[test]
+    fn every_chunk_type_is_read() {
+        let mut image = header(4, 28, 12);
+
+        chunk(&mut image, CHUNK_RAW, 2, 12 + 2 * BLOCK_SIZE);
+        image.extend(vec![0xaa; 2 * BLOCK_SIZE as usize]);
+        chunk(&mut image, CHUNK_FILL, 3, 16);
+        image.extend_from_slice(&0xdead_beefu32.to_le_bytes());
+        chunk(&mut image, CHUNK_DONT_CARE, 5, 12);
+        chunk(&mut image, CHUNK_CRC32, 0, 16);
+        image.extend_from_slice(&0xcafe_f00du32.to_le_bytes());
+
+        let mut image = Cursor::new(image);
+        let mut reader = SparseReader::new(&mut image).unwrap();
+
+        assert_eq!(reader.block_size, BLOCK_SIZE);
+        assert_eq!(reader.size(), 16 * BLOCK_SIZE as u64);
+        assert_eq!(reader.checksum, 0x1234_5678);
+
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), Some(Chunk::Raw(2 * BLOCK_SIZE as u64)));
+        let mut data = vec![0; 2 * BLOCK_SIZE as usize];
+        image.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|&b| b == 0xaa));
+
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), Some(Chunk::Fill(0xdead_beef, 3 * BLOCK_SIZE as u64)));
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), Some(Chunk::DontCare(5 * BLOCK_SIZE as u64)));
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), Some(Chunk::Crc32(0xcafe_f00d)));
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), None);
+    }
+
+    #[test]
+    fn header_extensions_are_skipped() {
+        let mut image = header(1, 32, 16);
+
+        chunk(&mut image, CHUNK_FILL, 1, 20);
+        image.extend_from_slice(&[0xff; 4]);
+        image.extend_from_slice(&7u32.to_le_bytes());
+
+        let mut image = Cursor::new(image);
+        let mut reader = SparseReader::new(&mut image).unwrap();
+
+        assert_eq!(reader.next_chunk(&mut image).unwrap(), Some(Chunk::Fill(7, BLOCK_SIZE as u64)));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let reason = |image: Vec<u8>| match SparseReader::new(&mut Cursor::new(image)) {
+            Err(Error::InvalidSparse(reason)) => reason,
+            _ => panic!("header accepted"),
+        };
+
+        let mut image = header(0, 28, 12);
+        image[0] = 0;
+        assert_eq!(reason(image), "bad magic");
+
+        let mut image = header(0, 28, 12);
+        image[4] = 2;
+        assert_eq!(reason(image), "unsupported major version");
+
+        let mut image = header(0, 28, 12);
+        image[10] = 8;
+        assert_eq!(reason(image), "bad header size");
+
+        let mut image = header(0, 28, 12);
+        image[12] = 2;
+        assert_eq!(reason(image), "bad block size");
+
+        assert_eq!(reason(header(0, 28, 12)[..20].to_vec()), "truncated image");
+    }
+
+    #[test]
+    fn bad_chunk_sizes_are_rejected() {
+        // Smaller than the chunk header itself
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, CHUNK_DONT_CARE, 1, 8);
+        assert_eq!(failure(image), "bad chunk size");
+
+        // Raw data shorter than its blocks
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, CHUNK_RAW, 2, 12 + BLOCK_SIZE);
+        assert_eq!(failure(image), "bad chunk size");
+
+        // Fill value wider than 32 bits
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, CHUNK_FILL, 1, 20);
+        assert_eq!(failure(image), "bad chunk size");
+
+        // Don't care chunks carry no data
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, CHUNK_DONT_CARE, 1, 16);
+        assert_eq!(failure(image), "bad chunk size");
+    }
+
+    #[test]
+    fn unknown_chunk_types_are_rejected() {
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, 0xcac5, 1, 12);
+
+        assert_eq!(failure(image), "unknown chunk type");
+    }
+
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        let mut image = header(1, 28, 12);
+        chunk(&mut image, CHUNK_FILL, 1, 16);
+        let complete = image.len();
+
+        // Cut in the chunk header
+        assert_eq!(failure(image[..complete - 4].to_vec()), "truncated image");
+
+        // Fill value missing
+        assert_eq!(failure(image), "truncated image");
+
+        // Chunk header extension missing
+        let mut image = header(1, 28, 16);
+        chunk(&mut image, CHUNK_DONT_CARE, 1, 16);
+        image.extend_from_slice(&[0; 2]);
+        assert_eq!(failure(image), "truncated image");
+    }
+}
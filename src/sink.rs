@@ -0,0 +1,94 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Destinations of the progress events.
+
+use std::io::{self,Write};
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender,SyncSender};
+
+use error::{Error,Result};
+use Progress;
+
+/// Receiver of the progress events of a burn
+pub trait ProgressSink {
+    /// Handles `event`.
+    ///
+    /// Failing means that nobody is listening anymore, which stops the burn
+    /// as if it was cancelled.
+    fn send(&self, event: Progress) -> Result<()>;
+}
+
+/// Events go to the receiving end of the channel, dropping it cancels the burn.
+impl ProgressSink for Sender<Progress> {
+    fn send(&self, event: Progress) -> Result<()> {
+        Sender::send(self, event).map_err(|_| Error::Cancelled)
+    }
+}
+
+/// As `Sender`, but blocks the burn while the channel is full.
+impl ProgressSink for SyncSender<Progress> {
+    fn send(&self, event: Progress) -> Result<()> {
+        SyncSender::send(self, event).map_err(|_| Error::Cancelled)
+    }
+}
+
+/// Closures are called with every event, in the burning thread. They can't
+/// stop the burn, a `CancelToken` can.
+impl<F: Fn(Progress)> ProgressSink for F {
+    fn send(&self, event: Progress) -> Result<()> {
+        self(event);
+
+        Ok(())
+    }
+}
+
+/// Drops every event.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullSink;
+
+impl ProgressSink for NullSink {
+    fn send(&self, _event: Progress) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes every event as a line of text.
+pub struct LogSink<W> {
+    output: Mutex<W>,
+}
+
+impl<W: Write> LogSink<W> {
+    pub fn new(output: W) -> LogSink<W> {
+        LogSink { output: Mutex::new(output) }
+    }
+}
+
+impl LogSink<io::Stderr> {
+    pub fn stderr() -> LogSink<io::Stderr> {
+        LogSink::new(io::stderr())
+    }
+}
+
+impl<W: Write> ProgressSink for LogSink<W> {
+    fn send(&self, event: Progress) -> Result<()> {
+        // A log that can't be written isn't a reason to stop the burn
+        if let Ok(mut output) = self.output.lock() {
+            let _ = writeln!(output, "{}", event);
+        }
+
+        Ok(())
+    }
+}
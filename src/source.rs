@@ -0,0 +1,883 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Image sources.
+//!
+//! Images may be stored raw or compressed. The format is sniffed from the
+//! magic bytes and the content is decompressed on the fly.
+
+use std::fs::File;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::path::Path;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::sync::mpsc::{self,Receiver};
+use std::thread;
+
+use bzip2::read::MultiBzDecoder;
+use crc32fast::Hasher as Crc32;
+use flate2::read::{DeflateDecoder,MultiGzDecoder};
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use archive;
+use checksum::{FileHash,HashAlgorithm};
+use error::{Error,Result};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Best ratio deflate can achieve
+const DEFLATE_MAX_RATIO: u64 = 1032;
+
+/// Size of the chunks handed to the branches of a `tee()`.
+const TEE_CHUNK: usize = 4 * 1024 * 1024; // 4 MiB
+/// Number of chunks a branch may lag behind.
+const TEE_DEPTH: usize = 2;
+
+/// Compression format of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+    /// ZIP archive holding the image
+    Zip,
+}
+
+impl Compression {
+    /// Identifies the format from the first bytes of a file.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else if header.starts_with(ZIP_MAGIC) {
+            Compression::Zip
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Digest of the image file, once requested, shared with the reader feeding it
+type SharedHash = Arc<Mutex<Option<FileHash>>>;
+
+/// Counts the bytes read through it, and hashes them when asked to.
+struct Counting<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+    hash: SharedHash,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+
+        if let Ok(mut hash) = self.hash.lock() {
+            if let Some(ref mut hash) = *hash {
+                hash.input(&buf[..n]);
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Checks the CRC-32 of an archive entry once read to its end.
+struct Crc32Check<R> {
+    inner: R,
+    crc: Crc32,
+    expected: u32,
+    name: String,
+}
+
+impl<R> Crc32Check<R> {
+    fn new(inner: R, entry: &archive::Entry) -> Crc32Check<R> {
+        Crc32Check { inner, crc: Crc32::new(), expected: entry.crc32, name: entry.name.clone() }
+    }
+}
+
+impl<R: Read> Read for Crc32Check<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        self.crc.update(&buf[..n]);
+
+        if n == 0 && !buf.is_empty() && self.crc.clone().finalize() != self.expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("{} doesn't match its CRC-32, the archive is corrupt", self.name)
+            ));
+        }
+
+        Ok(n)
+    }
+}
+
+/// Raw images can be seeked through, compressed ones only read.
+enum Reader {
+    Raw(Counting<File>),
+    Stream(Box<dyn Read + Send>),
+}
+
+/// Readable image, decompressed if needed
+pub struct ImageSource {
+    reader: Reader,
+    /// Image file, read through to hash what the burn doesn't read
+    file: Arc<File>,
+    /// Offset of the image data in the file, past the headers of archives
+    data_start: u64,
+    hash: SharedHash,
+    compression: Compression,
+    size: Option<u64>,
+    compressed_size: u64,
+    consumed: Arc<AtomicU64>,
+    entry: Option<String>,
+    /// Bytes read ahead by `peek()`, to be served first
+    peeked: Vec<u8>,
+}
+
+impl ImageSource {
+    /// Opens the image at `path`, whatever its compression.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ImageSource> {
+        ImageSource::open_entry(path, None)
+    }
+
+    /// Opens the image at `path`, picking `entry` if it is an archive.
+    ///
+    /// See `archive::find_entry` for how the image is chosen without `entry`.
+    pub fn open_entry<P: AsRef<Path>>(path: P, entry: Option<&str>) -> Result<ImageSource> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| Error::image_open(&path.to_string_lossy(), e))?;
+
+        ImageSource::open_file(file, path, entry)
+    }
+
+    /// As `open_entry`, with the image at `path` already opened as `file`,
+    /// read from its start.
+    pub fn open_file<P: AsRef<Path>>(mut file: File, path: P, entry: Option<&str>) -> Result<ImageSource> {
+        let path = path.as_ref();
+        let name = path.to_string_lossy();
+
+        file.seek(SeekFrom::Start(0))?;
+        let compressed_size = file.metadata()?.len();
+
+        let mut header = [0u8; 8];
+        let n = read_full(&mut file, &mut header)?;
+        let compression = Compression::detect(&header[..n]);
+
+        if compression == Compression::Zip {
+            let entry = archive::find_entry(&file, entry)?;
+            let shared = Arc::new(file.try_clone()?);
+            let consumed = Arc::new(AtomicU64::new(0));
+            let hash = SharedHash::default();
+
+            file.seek(SeekFrom::Start(entry.data_start))?;
+
+            let file = Counting {
+                inner: file.take(entry.compressed_size),
+                count: consumed.clone(),
+                hash: hash.clone(),
+            };
+
+            let reader: Box<dyn Read + Send> = if entry.deflated {
+                Box::new(Crc32Check::new(DeflateDecoder::new(file), &entry))
+            } else {
+                Box::new(Crc32Check::new(file, &entry))
+            };
+            let reader = Reader::Stream(reader);
+
+            return Ok(ImageSource {
+                reader,
+                file: shared,
+                data_start: entry.data_start,
+                hash,
+                compression,
+                size: Some(entry.size),
+                compressed_size: entry.compressed_size,
+                consumed,
+                entry: Some(entry.name),
+                peeked: Vec::new(),
+            });
+        }
+
+        if let Some(entry) = entry {
+            return Err(Error::InvalidArchive(format!("{} isn't an archive, can't pick {}", name, entry)));
+        }
+
+        let size = match compression {
+            Compression::None => Some(compressed_size),
+            Compression::Gzip => gzip_size(&mut file, compressed_size)?,
+            Compression::Xz => xz_size(&mut file, compressed_size)?,
+            Compression::Zstd => zstd_size(&mut file, compressed_size)?,
+            Compression::Bzip2 | Compression::Zip => None,
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+
+        let shared = Arc::new(file.try_clone()?);
+        let consumed = Arc::new(AtomicU64::new(0));
+        let hash = SharedHash::default();
+        let file = Counting {
+            inner: file,
+            count: consumed.clone(),
+            hash: hash.clone(),
+        };
+
+        let reader = match compression {
+            Compression::None => Reader::Raw(file),
+            Compression::Gzip => Reader::Stream(Box::new(MultiGzDecoder::new(file))),
+            Compression::Xz => Reader::Stream(Box::new(XzDecoder::new_multi_decoder(file))),
+            Compression::Bzip2 => Reader::Stream(Box::new(MultiBzDecoder::new(file))),
+            Compression::Zstd => Reader::Stream(Box::new(ZstdDecoder::new(file)?)),
+            Compression::Zip => unreachable!(),
+        };
+
+        Ok(ImageSource {
+            reader,
+            file: shared,
+            data_start: 0,
+            hash,
+            compression,
+            size,
+            compressed_size,
+            consumed,
+            entry: None,
+            peeked: Vec::new(),
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Name of the image inside the archive, if any.
+    pub fn entry(&self) -> Option<&str> {
+        self.entry.as_deref()
+    }
+
+    /// Size of the uncompressed image, when the container records it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Total to report progress against: the uncompressed size when known,
+    /// the compressed size otherwise.
+    pub fn total(&self) -> u64 {
+        self.size.unwrap_or(self.compressed_size)
+    }
+
+    /// Hashes the image file as it is read, see `file_digest()`.
+    ///
+    /// To be called before `tee()`. What was already read of the file, such
+    /// as the headers of an archive, is read again to be hashed.
+    pub fn hash_file(&mut self, algorithm: HashAlgorithm) -> io::Result<()> {
+        let mut hash = FileHash::new(self.file.clone(), algorithm);
+
+        hash.catch_up(Some(self.data_start + self.consumed.load(Ordering::Relaxed)))?;
+
+        if let Ok(mut shared) = self.hash.lock() {
+            *shared = Some(hash);
+        }
+
+        Ok(())
+    }
+
+    /// Digest of the image file, once read, reading what was left out.
+    ///
+    /// `None` unless requested with `hash_file()`.
+    pub fn file_digest(&self) -> Option<io::Result<Vec<u8>>> {
+        match self.hash.lock() {
+            Ok(mut hash) => hash.as_mut().map(FileHash::finish),
+            Err(_) => Some(Err(io::Error::other("image hashing panicked"))),
+        }
+    }
+
+    /// Progress made once `count` uncompressed bytes have been read,
+    /// in the same unit as `total()`.
+    pub fn position(&self, count: u64) -> u64 {
+        match self.size {
+            Some(_) => count,
+            None => self.consumed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ImageSource {
+    /// Skips the next `count` uncompressed bytes.
+    ///
+    /// Raw images are seeked through, compressed ones have to be decompressed anyway.
+    pub fn skip(&mut self, count: u64) -> io::Result<()> {
+        let buffered = (self.peeked.len() as u64).min(count);
+        self.peeked.drain(..buffered as usize);
+        let count = count - buffered;
+
+        let skipped = match self.reader {
+            Reader::Raw(ref mut file) => {
+                file.inner.seek(SeekFrom::Current(count as i64))?;
+                file.count.fetch_add(count, Ordering::Relaxed);
+
+                if let Ok(mut hash) = file.hash.lock() {
+                    if let Some(ref mut hash) = *hash {
+                        hash.catch_up(Some(count))?;
+                    }
+                }
+
+                count
+            }
+            Reader::Stream(ref mut reader) => io::copy(&mut reader.take(count), &mut io::sink())?,
+        };
+
+        if skipped < count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "image shorter than expected"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns up to `count` of the next uncompressed bytes, without consuming them.
+    pub fn peek(&mut self, count: usize) -> io::Result<&[u8]> {
+        let start = self.peeked.len();
+
+        if start < count {
+            self.peeked.resize(count, 0);
+
+            let mut filled = start;
+
+            while filled < count {
+                let n = match self.reader {
+                    Reader::Raw(ref mut file) => file.read(&mut self.peeked[filled..]),
+                    Reader::Stream(ref mut reader) => reader.read(&mut self.peeked[filled..]),
+                };
+
+                match n {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        self.peeked.truncate(filled);
+                        return Err(e);
+                    }
+                }
+            }
+
+            self.peeked.truncate(filled);
+        }
+
+        Ok(&self.peeked[..count.min(self.peeked.len())])
+    }
+}
+
+impl Read for ImageSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.peeked.is_empty() {
+            let n = buf.len().min(self.peeked.len());
+
+            buf[..n].copy_from_slice(&self.peeked[..n]);
+            self.peeked.drain(..n);
+
+            return Ok(n);
+        }
+
+        match self.reader {
+            Reader::Raw(ref mut file) => file.read(buf),
+            Reader::Stream(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+impl ImageSource {
+    /// Splits the image into `count` sources yielding the same data.
+    ///
+    /// The image is read and decompressed once, by a thread feeding every
+    /// branch, so the slowest branch sets the pace. A branch dropped early
+    /// stops being fed, the others carry on.
+    pub fn tee(mut self, count: usize) -> Vec<ImageSource> {
+        let mut senders = Vec::with_capacity(count);
+        let mut branches = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (tx, rx) = mpsc::sync_channel(TEE_DEPTH);
+            let consumed = Arc::new(AtomicU64::new(0));
+
+            senders.push(tx);
+            branches.push(ImageSource {
+                reader: Reader::Stream(Box::new(Branch {
+                    chunks: rx,
+                    chunk: Arc::new(Vec::new()),
+                    position: 0,
+                    consumed: consumed.clone(),
+                })),
+                file: self.file.clone(),
+                data_start: self.data_start,
+                hash: self.hash.clone(),
+                compression: self.compression,
+                size: self.size,
+                compressed_size: self.compressed_size,
+                consumed,
+                entry: self.entry.clone(),
+                peeked: Vec::new(),
+            });
+        }
+
+        thread::spawn(move || {
+            while !senders.is_empty() {
+                let mut chunk = vec![0u8; TEE_CHUNK];
+
+                let message = match read_full(&mut self, &mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Ok((Arc::new(chunk), self.consumed.load(Ordering::Relaxed)))
+                    }
+                    Err(e) => Err((e.kind(), e.to_string())),
+                };
+
+                // Branches whose reader is gone are forgotten
+                senders.retain(|tx| tx.send(message.clone()).is_ok());
+
+                if message.is_err() {
+                    break;
+                }
+            }
+        });
+
+        branches
+    }
+}
+
+/// Chunk of a teed image, with the source bytes consumed once it is read
+type TeeChunk = (Arc<Vec<u8>>, u64);
+
+/// Reading end of a `tee()`
+struct Branch {
+    chunks: Receiver<::std::result::Result<TeeChunk, (io::ErrorKind, String)>>,
+    chunk: Arc<Vec<u8>>,
+    position: usize,
+    consumed: Arc<AtomicU64>,
+}
+
+impl Read for Branch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(Ok((chunk, consumed))) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                    self.consumed.store(consumed, Ordering::Relaxed);
+                }
+                Ok(Err((kind, message))) => return Err(io::Error::new(kind, message)),
+                // The feeding thread is done
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.position);
+
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+
+        Ok(n)
+    }
+}
+
+/// Reads as much as possible into `buf`, stopping only at end of file.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    Ok(read_full(file, buf)? == buf.len())
+}
+
+fn le_u32(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// Size recorded in the gzip trailer (ISIZE).
+///
+/// ISIZE is the size of the last member only, modulo 4 GiB. It is thus only
+/// trusted for a single member stream too small to expand past 4 GiB, deflate
+/// not doing better than 1032:1.
+fn gzip_size(file: &mut File, len: u64) -> io::Result<Option<u64>> {
+    if len < 18 || len.saturating_mul(DEFLATE_MAX_RATIO) >= 1 << 32 {
+        return Ok(None);
+    }
+
+    let mut data = vec![0u8; len as usize];
+
+    if !read_at(file, 0, &mut data)? {
+        return Ok(None);
+    }
+
+    // Any other member starts with a header. Compressed data mimicking one
+    // only costs the hint.
+    if data[10..].windows(3).any(|bytes| bytes == [0x1f, 0x8b, 0x08]) {
+        return Ok(None);
+    }
+
+    Ok(Some(le_u32(&data[data.len() - 4..])))
+}
+
+/// Decodes a xz variable-length integer, advancing `pos`.
+fn xz_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+
+    for i in 0..9 {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Sum of the uncompressed sizes listed in the indexes of every xz stream.
+fn xz_size(file: &mut File, len: u64) -> io::Result<Option<u64>> {
+    let mut end = len;
+    let mut total = 0;
+
+    while end > 0 {
+        let mut footer = [0u8; 12];
+
+        if end < 24 || !read_at(file, end - 12, &mut footer)? {
+            return Ok(None);
+        }
+
+        // Stream padding
+        if footer[8..] == [0, 0, 0, 0] {
+            end -= 4;
+            continue;
+        }
+
+        if &footer[10..] != b"YZ" {
+            return Ok(None);
+        }
+
+        let index_size = (le_u32(&footer[4..8]) + 1) * 4;
+
+        if index_size + 24 > end {
+            return Ok(None);
+        }
+
+        let index_start = end - 12 - index_size;
+        let mut index = vec![0u8; index_size as usize];
+
+        if !read_at(file, index_start, &mut index)? || index[0] != 0 {
+            return Ok(None);
+        }
+
+        let mut pos = 1;
+        let mut blocks = 0;
+
+        let records = match xz_varint(&index, &mut pos) {
+            Some(records) => records,
+            None => return Ok(None),
+        };
+
+        for _ in 0..records {
+            match (xz_varint(&index, &mut pos), xz_varint(&index, &mut pos)) {
+                (Some(unpadded), Some(uncompressed)) => {
+                    blocks += (unpadded + 3) & !3;
+                    total += uncompressed;
+                }
+                _ => return Ok(None),
+            }
+        }
+
+        match index_start.checked_sub(blocks + 12) {
+            Some(start) => end = start,
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(total))
+}
+
+/// Sum of the content sizes recorded in the headers of every zstd frame.
+///
+/// Frames are skipped block by block, a frame without a recorded size makes
+/// the total unknown.
+fn zstd_size(file: &mut File, len: u64) -> io::Result<Option<u64>> {
+    let mut pos = 0;
+    let mut total = 0;
+
+    while pos < len {
+        let mut header = [0u8; 5];
+
+        if !read_at(file, pos, &mut header[..4])? {
+            return Ok(None);
+        }
+
+        // Skippable frames only hold metadata
+        if le_u32(&header[..4]) & 0xffff_fff0 == 0x184d_2a50 {
+            if !read_at(file, pos + 4, &mut header[..4])? {
+                return Ok(None);
+            }
+
+            pos += 8 + le_u32(&header[..4]);
+            continue;
+        }
+
+        if &header[..4] != ZSTD_MAGIC || !read_at(file, pos, &mut header)? {
+            return Ok(None);
+        }
+
+        let descriptor = header[4];
+        let single_segment = descriptor & 0x20 != 0;
+        let window: u64 = if single_segment { 0 } else { 1 };
+        let dictionary: u64 = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let size_length: usize = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        let mut field = [0u8; 8];
+
+        pos += 5 + window + dictionary;
+
+        if !read_at(file, pos, &mut field[..size_length])? {
+            return Ok(None);
+        }
+
+        let size = field.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64);
+
+        // The 2 bytes form is offset by 256
+        total += if size_length == 2 { size + 256 } else { size };
+        pos += size_length as u64;
+
+        loop {
+            let mut block = [0u8; 3];
+
+            if !read_at(file, pos, &mut block)? {
+                return Ok(None);
+            }
+
+            let block = le_u32(&block);
+
+            pos += 3 + match (block >> 1) & 0x03 {
+                // RLE blocks hold a single byte, repeated
+                1 => 1,
+                3 => return Ok(None),
+                _ => block >> 3,
+            };
+
+            if block & 1 != 0 {
+                break;
+            }
+        }
+
+        // Content checksum
+        if descriptor & 0x04 != 0 {
+            pos += 4;
+        }
+    }
+
+    // Overshooting means that the last frame is truncated
+    Ok(if pos == len { Some(total) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{remove_file,write};
+    use std::io::{Cursor,Write};
+    use std::process;
+
+    use flate2::Compression as Level;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+    use zstd;
+
+    use checksum::Hasher;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Size hint of an image made of `content`
+    fn size_of(name: &str, content: &[u8]) -> Option<u64> {
+        let path = env::temp_dir().join(format!("acetylene-source-{}-{}", name, process::id()));
+
+        write(&path, content).unwrap();
+
+        let size = ImageSource::open(&path).unwrap().size();
+
+        let _ = remove_file(&path);
+        size
+    }
+
+    /// Bytes that don't compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn gzip_size_comes_from_a_single_member() {
+        assert_eq!(size_of("gzip", &gzip(&[0u8; 100_000])), Some(100_000));
+        assert_eq!(size_of("gzip-noise", &gzip(&noise(1000))), Some(1000));
+
+        let mut members = gzip(&[0u8; 1000]);
+        members.extend(gzip(&[1u8; 500]));
+        assert_eq!(size_of("gzip-members", &members), None);
+    }
+
+    #[test]
+    fn gzip_size_is_unknown_when_it_may_wrap() {
+        assert_eq!(size_of("gzip-large", &gzip(&noise(5_000_000))), None);
+    }
+
+    #[test]
+    fn zstd_size_sums_every_frame() {
+        let mut frames = zstd::bulk::compress(&[0u8; 100_000][..], 3).unwrap();
+        frames.extend(zstd::bulk::compress(&noise(300_000)[..], 3).unwrap());
+        // Skippable frame
+        frames.extend(&[0x50, 0x2a, 0x4d, 0x18, 2, 0, 0, 0, 0xaa, 0xbb]);
+        frames.extend(zstd::bulk::compress(&b"tail"[..], 3).unwrap());
+
+        assert_eq!(size_of("zstd", &frames), Some(400_004));
+
+        frames.truncate(frames.len() - 2);
+        assert_eq!(size_of("zstd-truncated", &frames), None);
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+
+        hasher.input(data);
+        hasher.result()
+    }
+
+    #[test]
+    fn file_digests_cover_what_is_skipped() {
+        let path = env::temp_dir().join(format!("acetylene-source-digest-{}", process::id()));
+        let content = noise(3 * 1024 * 1024);
+
+        write(&path, &content).unwrap();
+
+        let mut image = ImageSource::open(&path).unwrap();
+        let mut buffer = vec![0u8; 1000];
+
+        image.peek(4).unwrap();
+        image.hash_file(HashAlgorithm::Sha256).unwrap();
+        image.read_exact(&mut buffer).unwrap();
+        image.skip(1024 * 1024).unwrap();
+        image.read_exact(&mut buffer).unwrap();
+
+        // The digest is the one of the opened file, not of what is now at its path
+        remove_file(&path).unwrap();
+        write(&path, b"other image").unwrap();
+
+        assert_eq!(image.file_digest().unwrap().unwrap(), sha256(&content));
+
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn file_digests_of_archives_cover_the_whole_archive() {
+        let path = env::temp_dir().join(format!("acetylene-source-zip-digest-{}", process::id()));
+        let content = noise(100_000);
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+
+        archive.start_file("image.img", FileOptions::default()).unwrap();
+        archive.write_all(&content).unwrap();
+
+        let archive = archive.finish().unwrap().into_inner();
+
+        write(&path, &archive).unwrap();
+
+        let mut image = ImageSource::open(&path).unwrap();
+        let mut extracted = Vec::new();
+
+        image.hash_file(HashAlgorithm::Sha256).unwrap();
+        image.read_to_end(&mut extracted).unwrap();
+
+        assert_eq!(extracted, content);
+        assert_eq!(image.file_digest().unwrap().unwrap(), sha256(&archive));
+
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_archive_entries_fail_at_their_end() {
+        let path = env::temp_dir().join(format!("acetylene-source-zip-crc-{}", process::id()));
+        let content = noise(100_000);
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+
+        archive.start_file("image.img", FileOptions::default().compression_method(zip::CompressionMethod::Stored))
+            .unwrap();
+        archive.write_all(&content).unwrap();
+
+        let mut archive = archive.finish().unwrap().into_inner();
+        let start = archive.windows(content.len()).position(|window| window == &content[..]).unwrap();
+
+        archive[start + 50_000] ^= 1;
+        write(&path, &archive).unwrap();
+
+        let mut image = ImageSource::open(&path).unwrap();
+        let mut extracted = vec![0u8; content.len()];
+
+        // Every byte is there, the corruption only shows at the end
+        image.read_exact(&mut extracted).unwrap();
+        let error = image.read(&mut [0u8; 16]).unwrap_err();
+
+        let _ = remove_file(&path);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,401 @@
+// This file is part of acetylene - Fuel. Efficiently.
+//
+// acetylene is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// blowtorch is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with blowtorch. If not, see <http://www.gnu.org/licenses/>.
+
+//! Read-back verification of written devices.
+//!
+//! While writing, the data is hashed as a whole and per fixed-size block.
+//! The device is then read back and hashed the same way: the global digests
+//! tell whether the write succeeded, and the block digests where it didn't.
+//! Blocks are always hashed with SHA-256, the whole data with the chosen
+//! algorithms.
+
+use std::mem;
+use std::fs::File;
+use std::io::{Read,Seek,SeekFrom,ErrorKind};
+use std::time::Duration;
+
+use sha2::{Sha256,Digest};
+
+use cancel::CancelToken;
+use checksum::{Digests,HashAlgorithm,Hashers};
+use error::{Error,Result};
+use meter::Meter;
+use {Progress,ProgressSink};
+
+/// Granularity of the mismatch detection.
+pub const BLOCK_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+/// Digests of the written data
+pub struct Written {
+    /// Digests of the whole data
+    pub digests: Digests,
+    /// Digest of every `BLOCK_SIZE` block, the last one may be shorter.
+    /// Empty when not verifying.
+    pub blocks: Vec<Vec<u8>>,
+}
+
+/// Hashes a stream of data, as a whole and, when verifying, per block.
+pub struct Tracker {
+    whole: Hashers,
+    block: Option<Sha256>,
+    filled: usize,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl Tracker {
+    pub fn new(algorithms: &[HashAlgorithm], verify: bool) -> Tracker {
+        Tracker {
+            whole: Hashers::new(algorithms),
+            block: if verify { Some(Sha256::default()) } else { None },
+            filled: 0,
+            blocks: vec![],
+        }
+    }
+
+    pub fn input(&mut self, mut data: &[u8]) {
+        self.whole.input(data);
+
+        let block = match self.block {
+            Some(ref mut block) => block,
+            None => return,
+        };
+
+        while !data.is_empty() {
+            let n = (BLOCK_SIZE - self.filled).min(data.len());
+
+            block.update(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+
+            if self.filled == BLOCK_SIZE {
+                let block = mem::take(block);
+                self.blocks.push(block.finalize().to_vec());
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Written {
+        if let Some(block) = self.block.take() {
+            if self.filled > 0 {
+                self.blocks.push(block.finalize().to_vec());
+            }
+        }
+
+        Written {
+            digests: self.whole.result(),
+            blocks: self.blocks,
+        }
+    }
+}
+
+/// Asks the kernel to forget the cached pages of `file`, so that reads hit the device.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    // Advisory only: on failure the verification is merely less thorough
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(_file: &File) {}
+
+/// Reads back the first `length` bytes of `device` and compares them to `expected`.
+///
+/// A mismatch is reported through `Progress::VerifyFailed` with the offset
+/// of the first differing block.
+pub fn read_back(device: &str, length: u64, expected: &Written, interval: Option<Duration>, tx: &dyn ProgressSink,
+                 cancel: &CancelToken)
+    -> Result<()>
+{
+    let mut file = File::open(device).map_err(|e| Error::device_open(device, e))?;
+
+    drop_cache(&file);
+
+    let algorithms: Vec<HashAlgorithm> = expected.digests.iter().map(|(&algorithm, _)| algorithm).collect();
+    let mut whole = Hashers::new(&algorithms);
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    let mut count: u64 = 0;
+    let mut meter = Meter::new(interval);
+
+    for (index, digest) in expected.blocks.iter().enumerate() {
+        cancel.check()?;
+
+        let size = (length - count).min(BLOCK_SIZE as u64) as usize;
+        let mut filled = 0;
+
+        while filled < size {
+            match file.read(&mut buffer[filled..size]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Read { offset: count + filled as u64, cause: e }),
+            }
+        }
+
+        let mut block = Sha256::default();
+        block.update(&buffer[..filled]);
+        whole.input(&buffer[..filled]);
+
+        if filled < size || block.finalize()[..] != digest[..] {
+            let offset = (index * BLOCK_SIZE) as u64;
+
+            tx.send(Progress::VerifyFailed { offset }).map_err(|_| Error::Cancelled)?;
+
+            return Err(Error::VerifyMismatch { offset });
+        }
+
+        count += filled as u64;
+
+        if let Some(throughput) = meter.update(count, length) {
+            tx.send(Progress::Verifying {
+                count,
+                total: length,
+                throughput,
+            }).map_err(|_| Error::Cancelled)?;
+        }
+    }
+
+    if whole.result() != expected.digests {
+        tx.send(Progress::VerifyFailed { offset: 0 }).map_err(|_| Error::Cancelled)?;
+
+        return Err(Error::VerifyMismatch { offset: 0 });
+    }
+
+    Ok(())
+}
+
+/// Written part of a device
+#[derive(Clone, Debug)]
+pub struct Extent {
+    pub start: u64,
+    pub end: u64,
+    /// Digest of the data written in `[start, end)`
+    pub digest: Vec<u8>,
+}
+
+/// Reads back the `extents` of `device` and compares them to their digest.
+///
+/// Used when only parts of the device have been written. A mismatch is
+/// reported through `Progress::VerifyFailed` with the start of the extent.
+pub fn read_back_extents(device: &str, extents: &[Extent], interval: Option<Duration>, tx: &dyn ProgressSink,
+                         cancel: &CancelToken)
+    -> Result<()>
+{
+    let mut file = File::open(device).map_err(|e| Error::device_open(device, e))?;
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    let total = extents.iter().map(|extent| extent.end - extent.start).sum();
+    let mut count = 0;
+    let mut meter = Meter::new(interval);
+
+    drop_cache(&file);
+
+    for extent in extents {
+        let mut hasher = Sha256::default();
+        let mut offset = extent.start;
+
+        file.seek(SeekFrom::Start(offset))?;
+
+        while offset < extent.end {
+            cancel.check()?;
+
+            let size = (extent.end - offset).min(BLOCK_SIZE as u64) as usize;
+
+            match file.read(&mut buffer[..size]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    hasher.update(&buffer[..n]);
+                    offset += n as u64;
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Read { offset, cause: e }),
+            }
+        }
+
+        if offset < extent.end || hasher.finalize()[..] != extent.digest[..] {
+            tx.send(Progress::VerifyFailed { offset: extent.start }).map_err(|_| Error::Cancelled)?;
+
+            return Err(Error::VerifyMismatch { offset: extent.start });
+        }
+
+        count += extent.end - extent.start;
+
+        if let Some(throughput) = meter.update(count, total) {
+            tx.send(Progress::Verifying { count, total, throughput }).map_err(|_| Error::Cancelled)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{create_dir_all,remove_dir_all,write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::mpsc::{self,Receiver};
+
+    use NullSink;
+
+    /// Empty directory of fixtures, unique to the test `name`
+    fn fixtures(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("acetylene-verify-{}-{}", name, process::id()));
+
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Bytes that differ from one block to the next
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8 ^ (i / BLOCK_SIZE) as u8).collect()
+    }
+
+    /// Digests of `data`, fed to the tracker in uneven pieces.
+    fn track(data: &[u8]) -> Written {
+        let mut tracker = Tracker::new(&[HashAlgorithm::Sha256], true);
+
+        for piece in data.chunks(1_000_003) {
+            tracker.input(piece);
+        }
+        tracker.finish()
+    }
+
+    fn failures(rx: &Receiver<Progress>) -> Vec<u64> {
+        rx.try_iter().filter_map(|event| match event {
+            Progress::VerifyFailed { offset } => Some(offset),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn blocks_are_tracked_across_inputs() {
+        let content = data(2 * BLOCK_SIZE + 1000);
+        let written = track(&content);
+
+        assert_eq!(written.blocks.len(), 3);
+        assert_eq!(written.blocks[1], Sha256::digest(&content[BLOCK_SIZE..2 * BLOCK_SIZE]).to_vec());
+        assert_eq!(written.blocks[2], Sha256::digest(&content[2 * BLOCK_SIZE..]).to_vec());
+        assert_eq!(written.digests.get(HashAlgorithm::Sha256), Some(&Sha256::digest(&content)[..]));
+
+        assert!(Tracker::new(&[], false).finish().blocks.is_empty());
+    }
+
+    #[test]
+    fn corrupt_blocks_are_reported_at_their_offset() {
+        let root = fixtures("corrupt");
+        let device = root.join("device.img");
+        let path = device.to_str().unwrap();
+        let mut content = data(3 * BLOCK_SIZE + 1000);
+        let written = track(&content);
+        let (tx, rx) = mpsc::channel();
+
+        write(&device, &content).unwrap();
+        let intact = read_back(path, content.len() as u64, &written, None, &tx, &CancelToken::new());
+
+        content[2 * BLOCK_SIZE + 12345] ^= 1;
+        write(&device, &content).unwrap();
+        let corrupt = read_back(path, content.len() as u64, &written, None, &tx, &CancelToken::new());
+
+        write(&device, &content[..3 * BLOCK_SIZE + 10]).unwrap();
+        let truncated = read_back(path, content.len() as u64, &track(&content), None, &tx, &CancelToken::new());
+
+        let _ = remove_dir_all(&root);
+        assert!(intact.is_ok());
+        assert!(matches!(corrupt, Err(Error::VerifyMismatch { offset }) if offset == 2 * BLOCK_SIZE as u64));
+        assert!(matches!(truncated, Err(Error::VerifyMismatch { offset }) if offset == 3 * BLOCK_SIZE as u64));
+        assert_eq!(failures(&rx), [2 * BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64]);
+    }
+
+    #[test]
+    fn whole_digests_are_checked_too() {
+        let root = fixtures("whole");
+        let device = root.join("device.img");
+        let content = data(1000);
+        let mut written = track(&content);
+        let (tx, rx) = mpsc::channel();
+
+        written.digests = track(&content[1..]).digests;
+        write(&device, &content).unwrap();
+
+        let result = read_back(device.to_str().unwrap(), 1000, &written, None, &tx, &CancelToken::new());
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(result, Err(Error::VerifyMismatch { offset: 0 })));
+        assert_eq!(failures(&rx), [0]);
+    }
+
+    #[test]
+    fn extents_are_read_back_alone() {
+        let root = fixtures("extents");
+        let device = root.join("device.img");
+        let path = device.to_str().unwrap();
+        let mut content = data(BLOCK_SIZE + 50_000);
+        let extent = |start: usize, end: usize, content: &[u8]| Extent {
+            start: start as u64,
+            end: end as u64,
+            digest: Sha256::digest(&content[start..end]).to_vec(),
+        };
+        let extents = [extent(0, 1000, &content), extent(10_000, BLOCK_SIZE + 20_000, &content)];
+        let (tx, rx) = mpsc::channel();
+
+        // Outside of the extents, nothing is looked at
+        content[5000] ^= 1;
+        content[BLOCK_SIZE + 30_000] ^= 1;
+        write(&device, &content).unwrap();
+        let skipped = read_back_extents(path, &extents, None, &tx, &CancelToken::new());
+
+        content[BLOCK_SIZE + 19_999] ^= 1;
+        write(&device, &content).unwrap();
+        let corrupt = read_back_extents(path, &extents, None, &tx, &CancelToken::new());
+
+        write(&device, &content[..BLOCK_SIZE]).unwrap();
+        let truncated = read_back_extents(path, &extents[1..], None, &tx, &CancelToken::new());
+
+        let _ = remove_dir_all(&root);
+        assert!(skipped.is_ok());
+        assert!(matches!(corrupt, Err(Error::VerifyMismatch { offset: 10_000 })));
+        assert!(matches!(truncated, Err(Error::VerifyMismatch { offset: 10_000 })));
+        assert_eq!(failures(&rx), [10_000, 10_000]);
+    }
+
+    #[test]
+    fn read_backs_stop_once_cancelled() {
+        let root = fixtures("cancel");
+        let device = root.join("device.img");
+        let path = device.to_str().unwrap();
+        let content = data(1000);
+        let cancel = CancelToken::new();
+        let extents = [Extent { start: 0, end: 1000, digest: Sha256::digest(&content).to_vec() }];
+
+        write(&device, &content).unwrap();
+        cancel.cancel();
+
+        let stream = read_back(path, 1000, &track(&content), None, &NullSink, &cancel);
+        let extents = read_back_extents(path, &extents, None, &NullSink, &cancel);
+
+        let _ = remove_dir_all(&root);
+        assert!(matches!(stream, Err(Error::Cancelled)));
+        assert!(matches!(extents, Err(Error::Cancelled)));
+    }
+}